@@ -4,12 +4,15 @@
 
 #![deny(unsafe_code)]
 
-use proc_macro2::{Ident, Span, TokenStream};
-use quote::{quote, quote_spanned, ToTokens};
+use proc_macro2::{Delimiter, Ident, Span, TokenStream, TokenTree};
+use quote::{format_ident, quote, quote_spanned, ToTokens};
+use syn::ext::IdentExt;
+use syn::parse::{Parse, ParseStream};
+use syn::punctuated::Punctuated;
 use syn::spanned::Spanned;
 use syn::{
-    parse_macro_input, Data, DeriveInput, Error, Field, Fields, Index, Lit, Meta, MetaList,
-    NestedMeta, Result,
+    parenthesized, parse_macro_input, token, Data, DeriveInput, Error, Expr, Field, Fields, Index,
+    Lit, LitStr, Meta, MetaList, NestedMeta, Result, Token, Visibility,
 };
 
 #[proc_macro_derive(MetricStorage, attributes(metric))]
@@ -23,6 +26,7 @@ pub fn metric_storage(input: proc_macro::TokenStream) -> proc_macro::TokenStream
 
 fn expand(input: DeriveInput) -> Result<TokenStream> {
     let name = input.ident;
+    let vis = input.vis;
 
     let attrs = MetricAttrs::parse(&input.attrs, true)?;
 
@@ -32,6 +36,20 @@ fn expand(input: DeriveInput) -> Result<TokenStream> {
         Data::Union(_) => panic!("MetricsStorage can't be implemented for unions"),
     };
 
+    match attrs.backend.as_deref() {
+        None | Some("prometheus") => {}
+        Some("prometheus-client") => return expand_client(name, attrs, input.fields),
+        Some(backend) => {
+            return Err(Error::new(
+                name.span(),
+                format!(
+                    "unknown backend {:?}, expected \"prometheus\" or \"prometheus-client\"",
+                    backend
+                ),
+            ))
+        }
+    }
+
     let subsystem = attrs.subsystem.unwrap_or_else(|| "".to_string());
 
     let labels = attrs.labels.unwrap_or_default();
@@ -40,33 +58,40 @@ fn expand(input: DeriveInput) -> Result<TokenStream> {
         .map(|l| Ident::new(l, Span::call_site()))
         .collect();
 
-    let (init, reg) = match input.fields {
+    let static_labels = insert_const_labels(&attrs.static_labels);
+
+    let struct_labels: Vec<_> = labels
+        .iter()
+        .chain(attrs.static_labels.iter().map(|(name, _)| name))
+        .cloned()
+        .collect();
+
+    let local = if attrs.local {
+        local_storage(&name, &vis, &input.fields)?
+    } else {
+        quote! {}
+    };
+
+    let (groups, type_checks) = field_groups(&input.fields)?;
+    let (reg, unreg) = registrators(&groups, false);
+    let (reg_multi, unreg_multi) = registrators(&groups, true);
+    let registry_checks = registry_checks(&groups);
+
+    let init = match input.fields {
         Fields::Named(fields) => {
             let ident: Vec<_> = fields
                 .named
                 .iter()
                 .map(|field| field.ident.clone().unwrap())
                 .collect();
-            let reg = registrators(
-                fields
-                    .named
-                    .iter()
-                    .map(|field| field.ident.clone().unwrap()),
-            );
-            let init = initializers(fields.named.into_iter(), subsystem)?;
-            let init = quote! { Self { #(#ident: #init,)* } };
-            (init, reg)
+            let init = initializers(fields.named.into_iter(), subsystem, &struct_labels)?;
+            quote! { Self { #(#ident: #init,)* } }
         }
         Fields::Unnamed(fields) => {
-            let reg = registrators((0..fields.unnamed.len()).map(|i| Index {
-                index: i as _,
-                span: Span::call_site(),
-            }));
-            let init = initializers(fields.unnamed.into_iter(), subsystem)?;
-            let init = quote! { Self ( #(#init,)* ) };
-            (init, reg)
-        }
-        Fields::Unit => (quote! { Self }, quote! {}),
+            let init = initializers(fields.unnamed.into_iter(), subsystem, &struct_labels)?;
+            quote! { Self ( #(#init,)* ) }
+        }
+        Fields::Unit => quote! { Self },
     };
 
     Ok(quote! {
@@ -74,6 +99,8 @@ fn expand(input: DeriveInput) -> Result<TokenStream> {
             clippy::vec_init_then_push,
             clippy::redundant_clone,
             clippy::let_and_return,
+            clippy::useless_conversion,
+            clippy::needless_question_mark,
             unused,
             unused_mut
         )]
@@ -85,21 +112,72 @@ fn expand(input: DeriveInput) -> Result<TokenStream> {
             fn from_const_labels_unregistered(
                 const_labels: std::collections::HashMap<String, String>
             ) -> prometheus_metric_storage::Result<Self> {
+                prometheus_metric_storage::check_const_labels(&[#(#labels,)*], &const_labels)?;
+                <Self as prometheus_metric_storage::MetricStorage>::from_namespace_unregistered(
+                    "", const_labels
+                )
+            }
+
+            fn from_namespace_unregistered(
+                namespace: &str,
+                const_labels: std::collections::HashMap<String, String>
+            ) -> prometheus_metric_storage::Result<Self> {
+                let mut const_labels = const_labels;
+                #static_labels
+                let config = prometheus_metric_storage::metrics_config();
                 Ok(#init)
             }
 
             fn register(
                 &self, registry: &prometheus_metric_storage::Registry
             ) -> prometheus_metric_storage::Result<()> {
+                let config = prometheus_metric_storage::metrics_config();
+                let mut registration = prometheus_metric_storage::Registration::new();
                 #reg
+                registration.commit();
                 Ok(())
             }
+
+            fn register_multi(
+                &self, registries: &[(&str, &prometheus_metric_storage::Registry)]
+            ) -> prometheus_metric_storage::Result<()> {
+                #registry_checks
+                let config = prometheus_metric_storage::metrics_config();
+                let mut registration = prometheus_metric_storage::Registration::new();
+                for &(name, registry) in registries {
+                    #reg_multi
+                }
+                registration.commit();
+                Ok(())
+            }
+
+            fn unregister(
+                &self, registry: &prometheus_metric_storage::Registry
+            ) -> prometheus_metric_storage::Result<()> {
+                let config = prometheus_metric_storage::metrics_config();
+                let mut failed = Vec::new();
+                #unreg
+                prometheus_metric_storage::collect_failures(failed)
+            }
+
+            fn unregister_multi(
+                &self, registries: &[(&str, &prometheus_metric_storage::Registry)]
+            ) -> prometheus_metric_storage::Result<()> {
+                let config = prometheus_metric_storage::metrics_config();
+                let mut failed = Vec::new();
+                for &(name, registry) in registries {
+                    #unreg_multi
+                }
+                prometheus_metric_storage::collect_failures(failed)
+            }
         }
 
         #[allow(
             clippy::vec_init_then_push,
             clippy::redundant_clone,
             clippy::let_and_return,
+            clippy::useless_conversion,
+            clippy::needless_question_mark,
             unused,
             unused_mut
         )]
@@ -121,6 +199,15 @@ fn expand(input: DeriveInput) -> Result<TokenStream> {
                 Ok(metrics)
             }
 
+            fn new_multi(
+                registries: &[(&str, &prometheus_metric_storage::Registry)],
+                #(#label_idents: impl Into<String>,)*
+            ) -> prometheus_metric_storage::Result<Self> {
+                let metrics = Self::new_unregistered(#(#label_idents,)*)?;
+                <Self as prometheus_metric_storage::MetricStorage>::register_multi(&metrics, registries)?;
+                Ok(metrics)
+            }
+
             fn instance(
                 registry: &prometheus_metric_storage::StorageRegistry, #(#label_idents: impl Into<String>,)*
             ) -> prometheus_metric_storage::Result<&Self> {
@@ -130,53 +217,338 @@ fn expand(input: DeriveInput) -> Result<TokenStream> {
                 registry.get_or_create_storage::<Self>(const_labels)
             }
         }
+
+        #local
+
+        #type_checks
+    })
+}
+
+fn expand_client(name: Ident, attrs: MetricAttrs, fields: Fields) -> Result<TokenStream> {
+    if attrs.local {
+        return Err(Error::new(
+            name.span(),
+            "`local` is not supported by the prometheus-client backend",
+        ));
+    }
+
+    let subsystem = attrs.subsystem.unwrap_or_default();
+
+    let labels = attrs.labels.unwrap_or_default();
+    let label_idents: Vec<_> = labels
+        .iter()
+        .map(|l| Ident::new(l, Span::call_site()))
+        .collect();
+
+    let static_labels = attrs.static_labels.iter().map(|(name, value)| {
+        quote_spanned! { value.span() =>
+            (#name, ToString::to_string(&(#value)))
+        }
+    });
+
+    let mut ident = Vec::new();
+    let mut init = Vec::new();
+    let mut metric_name = Vec::new();
+    let mut metric_help = Vec::new();
+
+    for (i, field) in fields.iter().enumerate() {
+        let MetricAttrs {
+            name,
+            help,
+            labels,
+            buckets,
+            max_series,
+            overflow,
+            ttl_seconds,
+            registries,
+            const_labels,
+            init: init_fn,
+            skip,
+            const_label,
+            ..
+        } = MetricAttrs::parse(&field.attrs, false)?;
+
+        if skip || const_label.is_some() {
+            return Err(Error::new(
+                field.span(),
+                "`skip` and `const_label` are not supported by the prometheus-client backend",
+            ));
+        }
+
+        if labels.is_some() {
+            return Err(Error::new(
+                field.span(),
+                "variable labels are defined by the label set of a `Family` \
+                 when using the prometheus-client backend",
+            ));
+        }
+
+        if max_series.is_some() || overflow.is_some() || ttl_seconds.is_some() {
+            return Err(Error::new(
+                field.span(),
+                "`max_series`, `overflow` and `ttl_seconds` are not supported \
+                 by the prometheus-client backend",
+            ));
+        }
+
+        if registries.is_some() {
+            return Err(Error::new(
+                field.span(),
+                "`registries` is not supported by the prometheus-client backend",
+            ));
+        }
+
+        if const_labels.is_some() || init_fn.is_some() {
+            return Err(Error::new(
+                field.span(),
+                "`const_labels` and `init` are not supported by the prometheus-client backend",
+            ));
+        }
+
+        let (name, help) = name_and_help(field, name, help)?;
+
+        ident.push(match &field.ident {
+            Some(ident) => ident.to_token_stream(),
+            None => Index::from(i).to_token_stream(),
+        });
+        init.push(match buckets {
+            Some(buckets) => quote_spanned! { field.span() =>
+                prometheus_metric_storage::client::HistMetricInit::init(vec![#(#buckets,)*])
+            },
+            None => quote_spanned! { field.span() =>
+                prometheus_metric_storage::client::MetricInit::init()
+            },
+        });
+        metric_name.push(name);
+        metric_help.push(help);
+    }
+
+    let init = match fields {
+        Fields::Named(_) => quote! { Self { #(#ident: #init,)* } },
+        Fields::Unnamed(_) => quote! { Self ( #(#init,)* ) },
+        Fields::Unit => quote! { Self },
+    };
+
+    Ok(quote! {
+        #[allow(clippy::redundant_clone, clippy::let_and_return, unused, unused_mut)]
+        impl prometheus_metric_storage::client::MetricStorage for #name {
+            fn const_labels() -> &'static [&'static str] {
+                &[#(#labels,)*]
+            }
+
+            fn from_const_labels(
+                registry: &mut prometheus_metric_storage::client::Registry,
+                const_labels: std::collections::HashMap<String, String>,
+            ) -> prometheus_metric_storage::Result<Self> {
+                let registry = prometheus_metric_storage::client::sub_registry(
+                    registry,
+                    #subsystem,
+                    <Self as prometheus_metric_storage::client::MetricStorage>::const_labels(),
+                    const_labels,
+                    vec![#(#static_labels,)*],
+                )?;
+                let storage = #init;
+                #(
+                    prometheus_metric_storage::client::register(
+                        registry, #metric_name, #metric_help, storage.#ident.clone()
+                    );
+                )*
+                Ok(storage)
+            }
+        }
+
+        #[allow(unused)]
+        impl #name {
+            fn new(
+                registry: &mut prometheus_metric_storage::client::Registry,
+                #(#label_idents: impl Into<String>,)*
+            ) -> prometheus_metric_storage::Result<Self> {
+                let mut const_labels = std::collections::HashMap::new();
+                #(const_labels.insert(#labels.to_string(), #label_idents.into());)*
+
+                <Self as prometheus_metric_storage::client::MetricStorage>::from_const_labels(
+                    registry, const_labels
+                )
+            }
+        }
+    })
+}
+
+fn local_storage(name: &Ident, vis: &Visibility, fields: &Fields) -> Result<TokenStream> {
+    let local_name = format_ident!("{}Local", name);
+    let doc = format!("Thread-local counterpart of [`{}`].", name);
+
+    let mut metric_fields = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        if MetricAttrs::parse(&field.attrs, false)?.is_metric() {
+            metric_fields.push((i, field));
+        }
+    }
+
+    let ty: Vec<_> = metric_fields.iter().map(|(_, field)| &field.ty).collect();
+    let field_vis: Vec<_> = metric_fields.iter().map(|(_, field)| &field.vis).collect();
+    let field_doc: Vec<Vec<_>> = metric_fields
+        .iter()
+        .map(|(_, field)| {
+            field
+                .attrs
+                .iter()
+                .filter(|attr| attr.path.is_ident("doc"))
+                .collect()
+        })
+        .collect();
+    let ident: Vec<_> = metric_fields
+        .iter()
+        .map(|(i, field)| match &field.ident {
+            Some(ident) => ident.to_token_stream(),
+            None => Index::from(*i).to_token_stream(),
+        })
+        .collect();
+    let local_ident: Vec<_> = metric_fields
+        .iter()
+        .enumerate()
+        .map(|(i, (_, field))| match &field.ident {
+            Some(ident) => ident.to_token_stream(),
+            None => Index::from(i).to_token_stream(),
+        })
+        .collect();
+
+    let (def, init) = match fields {
+        Fields::Named(_) => (
+            quote! { { #(#(#field_doc)* #field_vis #ident: <#ty as prometheus_metric_storage::MetricLocal>::Local,)* } },
+            quote! { #local_name { #(#ident: prometheus_metric_storage::MetricLocal::local(&self.#ident),)* } },
+        ),
+        Fields::Unnamed(_) => (
+            quote! { ( #(#(#field_doc)* #field_vis <#ty as prometheus_metric_storage::MetricLocal>::Local,)* ); },
+            quote! { #local_name ( #(prometheus_metric_storage::MetricLocal::local(&self.#ident),)* ) },
+        ),
+        Fields::Unit => (quote! { ; }, quote! { #local_name }),
+    };
+
+    Ok(quote! {
+        #[doc = #doc]
+        #vis struct #local_name #def
+
+        #[allow(unused)]
+        impl #name {
+            /// Create a thread-local counterpart of this storage.
+            fn local(&self) -> #local_name {
+                #init
+            }
+        }
+
+        #[allow(unused)]
+        impl #local_name {
+            /// Flush all buffered updates to the shared metrics.
+            fn flush(&self) {
+                #(<#ty as prometheus_metric_storage::MetricLocal>::flush(&self.#local_ident);)*
+            }
+        }
+
+        impl Drop for #local_name {
+            fn drop(&mut self) {
+                self.flush();
+            }
+        }
     })
 }
 
 fn initializers(
     fields: impl Iterator<Item = Field>,
     subsystem: String,
+    struct_labels: &[String],
 ) -> Result<Vec<TokenStream>> {
     fields
-        .map(|field| {
+        .enumerate()
+        .map(|(i, field)| {
             let MetricAttrs {
                 name,
                 help,
                 labels,
+                const_labels,
                 buckets,
+                max_series,
+                overflow,
+                ttl_seconds,
+                init,
+                skip,
+                default,
+                const_label,
                 ..
             } = MetricAttrs::parse(&field.attrs, false)?;
 
-            let name = name.or_else(|| field.ident.as_ref().map(|ident| ident.to_string()));
-            let name = match name {
-                Some(name) if !name.is_empty() => name,
-                _ => {
+            if skip {
+                return Ok(match default {
+                    Some(default) => default.to_token_stream(),
+                    None => quote_spanned! { field.span() => Default::default() },
+                });
+            }
+
+            if let Some(label) = const_label {
+                let label = match (label, &field.ident) {
+                    (Some(label), _) => label,
+                    (None, Some(ident)) => ident.unraw().to_string(),
+                    (None, None) => {
+                        return Err(Error::new(
+                            field.span(),
+                            "label name is required, consider adding `const_label = \"...\"`",
+                        ))
+                    }
+                };
+
+                if !struct_labels.contains(&label) {
                     return Err(Error::new(
                         field.span(),
-                        "metric name is required, consider adding `#[metric(name = \"...\")]`",
-                    ))
+                        format!(
+                            "label {:?} is not declared in `#[metric(labels(...))]`",
+                            label
+                        ),
+                    ));
                 }
+
+                return Ok(quote_spanned! { field.span() =>
+                    match const_labels.get(#label) {
+                        Some(value) => value.clone().into(),
+                        None => {
+                            return Err(prometheus_metric_storage::Error::MissingConstLabel {
+                                label: #label.to_string(),
+                            })
+                        }
+                    }
+                });
+            }
+
+            let (name, help) = name_and_help(&field, name, help)?;
+
+            let field_name = match &field.ident {
+                Some(ident) => ident.to_string(),
+                None => i.to_string(),
             };
 
-            let help = match help {
-                Some(help) if !help.is_empty() => help,
-                _ => {
+            let labels = labels.unwrap_or_default();
+
+            let const_labels = const_labels.unwrap_or_default();
+            for (label, _) in &const_labels {
+                if struct_labels.contains(label) || labels.contains(label) {
                     return Err(Error::new(
                         field.span(),
-                        "metric help message is required, consider adding a docstring",
-                    ))
+                        format!("label {:?} is already defined", label),
+                    ));
                 }
-            };
-
-            let labels = labels.unwrap_or_default();
+            }
+            let const_labels = insert_const_labels(&const_labels);
 
             let opts = quote_spanned! { field.span() =>
                 prometheus_metric_storage::Opts {
-                    namespace: "".to_string(),
+                    namespace: namespace.to_string(),
                     subsystem: #subsystem.to_string(),
                     name: #name.to_string(),
                     help: #help.to_string(),
-                    const_labels: const_labels.clone(),
+                    const_labels: {
+                        let mut const_labels = const_labels.clone();
+                        #const_labels
+                        const_labels
+                    },
                     variable_labels: {
                         let mut labels = Vec::new();
                         #(labels.push(#labels.to_string());)*
@@ -185,37 +557,384 @@ fn initializers(
                 }
             };
 
-            if let Some(buckets) = buckets {
-                Ok(quote_spanned! { field.span() =>
-                    prometheus_metric_storage::HistMetricInit::init(
-                        #opts,
-                        {
-                            let mut buckets = Vec::new();
-                            #(buckets.push(#buckets);)*
-                            buckets
+            let init_fn = match (init, &buckets) {
+                (Some(init), _) => init.to_token_stream(),
+                (None, Some(_)) => quote! { prometheus_metric_storage::HistMetricInit::init },
+                (None, None) => quote! { prometheus_metric_storage::MetricInit::init },
+            };
+
+            let mut init = if let Some(buckets) = buckets {
+                quote_spanned! { field.span() =>
+                    #init_fn(
+                        opts.clone(),
+                        match config.buckets(&opts.fq_name()) {
+                            Some(buckets) => buckets.to_vec(),
+                            None => {
+                                let mut buckets = Vec::new();
+                                #(buckets.push(#buckets);)*
+                                buckets
+                            }
                         }
                     )?
-                })
+                }
             } else {
-                Ok(quote! {
-                    prometheus_metric_storage::MetricInit::init(#opts)?
-                })
+                quote_spanned! { field.span() =>
+                    #init_fn(opts.clone())?
+                }
+            };
+
+            match (max_series, overflow) {
+                (Some(max_series), overflow) => {
+                    let overflow = overflow.unwrap_or_else(|| "other".to_string());
+                    init = quote_spanned! { field.span() =>
+                        prometheus_metric_storage::LimitedVec::new(
+                            #init, &opts, #max_series, #overflow
+                        )?
+                    };
+                }
+                (None, Some(_)) => {
+                    return Err(Error::new(
+                        field.span(),
+                        "`overflow` requires `max_series` to be set",
+                    ))
+                }
+                (None, None) => {}
             }
+
+            if let Some(ttl_seconds) = ttl_seconds {
+                if max_series.is_some() {
+                    return Err(Error::new(
+                        field.span(),
+                        "`ttl_seconds` can't be combined with `max_series`",
+                    ));
+                }
+
+                init = quote_spanned! { field.span() =>
+                    prometheus_metric_storage::ExpiringVec::new(
+                        #init, std::time::Duration::from_secs(#ttl_seconds)
+                    )
+                };
+            }
+
+            Ok(quote! {
+                {
+                    let mut opts = #opts;
+                    config.apply(&mut opts);
+                    prometheus_metric_storage::init_field(#field_name, &opts, || Ok(#init))?
+                }
+            })
         })
         .collect()
 }
 
-fn registrators<I: Iterator<Item = T>, T: ToTokens>(ident: I) -> TokenStream {
-    quote! { #(registry.register(Box::new(self.#ident.clone()))?;)* }
+/// Generate code that adds const labels with the given values
+/// to the `const_labels` map.
+fn insert_const_labels(labels: &[(String, Expr)]) -> TokenStream {
+    let insert = labels.iter().map(|(name, value)| {
+        quote_spanned! { value.span() =>
+            if const_labels.contains_key(#name) {
+                return Err(prometheus_metric_storage::Error::DuplicateConstLabel {
+                    label: #name.to_string(),
+                });
+            }
+            const_labels.insert(#name.to_string(), ToString::to_string(&(#value)));
+        }
+    });
+
+    quote! { #(#insert)* }
+}
+
+fn name_and_help(
+    field: &Field,
+    name: Option<String>,
+    help: Option<String>,
+) -> Result<(String, String)> {
+    let name = name.or_else(|| field.ident.as_ref().map(|ident| ident.to_string()));
+    let name = match name {
+        Some(name) if !name.is_empty() => name,
+        _ => {
+            return Err(Error::new(
+                field.span(),
+                "metric name is required, consider adding `#[metric(name = \"...\")]`",
+            ))
+        }
+    };
+
+    let help = match help {
+        Some(help) if !help.is_empty() => help,
+        _ => {
+            return Err(Error::new(
+                field.span(),
+                "metric help message is required, consider adding a docstring",
+            ))
+        }
+    };
+
+    Ok((name, help))
+}
+
+/// Fields that are registered together.
+///
+/// Fields that share a metric name form a group, and are registered
+/// as a single collector. Other fields are registered on their own.
+struct FieldGroup {
+    idents: Vec<TokenStream>,
+
+    /// Names of field-level const labels that tell fields of a group apart.
+    labels: Vec<String>,
+
+    registries: Option<Vec<String>>,
+}
+
+/// Split fields into groups, and check that fields of each group
+/// can be combined into a single metric.
+///
+/// Returns groups and code that checks that fields of each group
+/// have the same type.
+fn field_groups(fields: &Fields) -> Result<(Vec<FieldGroup>, TokenStream)> {
+    struct Member<'a> {
+        field: &'a Field,
+        ident: TokenStream,
+        attrs: MetricAttrs,
+        help: String,
+    }
+
+    let mut groups: Vec<(String, Vec<Member>)> = Vec::new();
+
+    for (i, field) in fields.iter().enumerate() {
+        let ident = match &field.ident {
+            Some(ident) => ident.to_token_stream(),
+            None => Index::from(i).to_token_stream(),
+        };
+        let mut attrs = MetricAttrs::parse(&field.attrs, false)?;
+        if !attrs.is_metric() {
+            continue;
+        }
+        let (name, help) = name_and_help(field, attrs.name.take(), attrs.help.take())?;
+
+        let member = Member {
+            field,
+            ident,
+            attrs,
+            help,
+        };
+        match groups
+            .iter_mut()
+            .find(|(group_name, _)| *group_name == name)
+        {
+            Some((_, members)) => members.push(member),
+            None => groups.push((name, vec![member])),
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut type_checks = Vec::new();
+
+    for (name, members) in groups {
+        let first = &members[0];
+
+        let mut labels: Vec<String> = Vec::new();
+        if members.len() > 1 {
+            let error = |member: &Member, message: &str| {
+                Err(Error::new(
+                    member.field.span(),
+                    format!("fields that share metric name {:?} {}", name, message),
+                ))
+            };
+
+            labels = match &first.attrs.const_labels {
+                Some(const_labels) => const_labels.iter().map(|(l, _)| l.clone()).collect(),
+                None => Vec::new(),
+            };
+
+            let mut seen_values = Vec::new();
+            for (i, member) in members.iter().enumerate() {
+                let const_labels = member.attrs.const_labels.as_deref().unwrap_or_default();
+
+                let mut names: Vec<_> = const_labels.iter().map(|(l, _)| l).collect();
+                let mut expected: Vec<_> = labels.iter().collect();
+                names.sort();
+                expected.sort();
+                if names.is_empty() || names != expected {
+                    return error(
+                        member,
+                        "should have field-level const labels with the same names",
+                    );
+                }
+
+                if member.help != first.help {
+                    return error(member, "should have the same help message");
+                }
+
+                if member.attrs.labels != first.attrs.labels {
+                    return error(member, "should have the same labels");
+                }
+
+                if member.attrs.registries != first.attrs.registries {
+                    return error(member, "should be registered in the same registries");
+                }
+
+                if member.attrs.max_series.is_some() {
+                    return error(member, "can't use `max_series`");
+                }
+
+                // Values that are string literals can be checked right away.
+                let values: Option<Vec<_>> = labels
+                    .iter()
+                    .map(|label| {
+                        let (_, value) = const_labels.iter().find(|(l, _)| l == label)?;
+                        match value {
+                            Expr::Lit(syn::ExprLit {
+                                lit: Lit::Str(value),
+                                ..
+                            }) => Some(value.value()),
+                            _ => None,
+                        }
+                    })
+                    .collect();
+                if let Some(values) = values {
+                    if seen_values.contains(&values) {
+                        return error(member, "should have different values of const labels");
+                    }
+                    seen_values.push(values);
+                }
+
+                if i > 0 {
+                    let (first_ty, ty) = (&first.field.ty, &member.field.ty);
+                    type_checks.push(quote_spanned! { ty.span() =>
+                        const _: fn(&#first_ty) -> &#ty = |metric| metric;
+                    });
+                }
+            }
+        }
+
+        result.push(FieldGroup {
+            registries: first.attrs.registries.clone(),
+            idents: members.into_iter().map(|member| member.ident).collect(),
+            labels,
+        });
+    }
+
+    Ok((result, quote! { #(#type_checks)* }))
+}
+
+/// Generate code that checks that each field group with the `registries`
+/// option is routed to at least one of the given registries.
+fn registry_checks(groups: &[FieldGroup]) -> TokenStream {
+    let checks = groups.iter().filter_map(|group| {
+        let names = group.registries.as_ref()?;
+        let field = group_field_names(group);
+        Some(quote! {
+            prometheus_metric_storage::check_registry_names(registries, #field, &[#(#names),*])?;
+        })
+    });
+
+    quote! { #(#checks)* }
+}
+
+/// Names of fields of a group, used in error messages.
+fn group_field_names(group: &FieldGroup) -> String {
+    group
+        .idents
+        .iter()
+        .map(|ident| ident.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Generate code that registers and unregisters field groups.
+///
+/// If `multi` is set, the code is placed in a loop over named registries,
+/// and fields are routed according to their `registries` option.
+fn registrators(groups: &[FieldGroup], multi: bool) -> (TokenStream, TokenStream) {
+    let mut reg = Vec::new();
+    let mut unreg = Vec::new();
+
+    for group in groups {
+        let ident = &group.idents;
+        let field = group_field_names(group);
+
+        let (group_reg, group_unreg) = match ident.as_slice() {
+            [ident] => (
+                quote! {
+                    if config.is_collector_enabled(&self.#ident) {
+                        registration.register(registry, #field, &self.#ident)?;
+                    }
+                },
+                quote! {
+                    if config.is_collector_enabled(&self.#ident) {
+                        if let Err(err) = prometheus_metric_storage::unregister_field(registry, #field, &self.#ident) {
+                            failed.push(err);
+                        }
+                    }
+                },
+            ),
+            _ => {
+                let labels = &group.labels;
+                let group = quote! {
+                    let mut group = prometheus_metric_storage::MetricGroupBuilder::new(&[#(#labels),*]);
+                    #(
+                        if config.is_collector_enabled(&self.#ident) {
+                            group.push(&self.#ident);
+                        }
+                    )*
+                };
+                (
+                    quote! {
+                        {
+                            #group
+                            registration.register_group(registry, #field, group)?;
+                        }
+                    },
+                    quote! {
+                        {
+                            #group
+                            if let Err(err) = prometheus_metric_storage::unregister_group(registry, #field, group) {
+                                failed.push(err);
+                            }
+                        }
+                    },
+                )
+            }
+        };
+
+        match (&group.registries, multi) {
+            (Some(names), true) => {
+                reg.push(quote! { if [#(#names),*].contains(&name) { #group_reg } });
+                unreg.push(quote! { if [#(#names),*].contains(&name) { #group_unreg } });
+            }
+            _ => {
+                reg.push(group_reg);
+                unreg.push(group_unreg);
+            }
+        }
+    }
+
+    (quote! { #(#reg)* }, quote! { #(#unreg)* })
 }
 
-#[derive(Default, Debug)]
+#[derive(Default)]
 struct MetricAttrs {
     subsystem: Option<String>,
     name: Option<String>,
     help: Option<String>,
     labels: Option<Vec<String>>,
+    static_labels: Vec<(String, Expr)>,
+    const_labels: Option<Vec<(String, Expr)>>,
+    init: Option<syn::Path>,
     buckets: Option<Vec<f64>>,
+    max_series: Option<usize>,
+    overflow: Option<String>,
+    ttl_seconds: Option<u64>,
+    local: bool,
+    backend: Option<String>,
+    registries: Option<Vec<String>>,
+    skip: bool,
+    default: Option<Expr>,
+
+    /// Set for fields that hold a value of a const label. Contains
+    /// the label name if it differs from the field name.
+    const_label: Option<Option<String>>,
 }
 
 impl MetricAttrs {
@@ -223,11 +942,18 @@ impl MetricAttrs {
         let mut result = Self::default();
 
         let mut doc = None;
+        let mut span = None;
 
         for attr in attrs {
             if attr.path.is_ident("metric") {
-                let list = match attr.parse_meta()? {
-                    Meta::List(list) => list,
+                span = Some(attr.path.span());
+
+                let args = match attr.tokens.clone().into_iter().next() {
+                    Some(TokenTree::Group(group))
+                        if group.delimiter() == Delimiter::Parenthesis =>
+                    {
+                        attr.parse_args_with(Punctuated::<MetricArg, Token![,]>::parse_terminated)?
+                    }
                     _ => {
                         return Err(Error::new(
                             attr.path.span(),
@@ -236,25 +962,58 @@ impl MetricAttrs {
                     }
                 };
 
-                for attr in list.nested {
-                    let attr = match attr {
-                        NestedMeta::Meta(attr) => attr,
-                        NestedMeta::Lit(lit) => {
+                for arg in args {
+                    let attr = match arg {
+                        MetricArg::Labels(ident, labels) if ident == "labels" => {
+                            result.parse_labels(ident, labels, is_struct_level)?;
+                            continue;
+                        }
+                        MetricArg::Labels(ident, labels) if !is_struct_level => {
+                            result.parse_const_labels(ident, labels)?;
+                            continue;
+                        }
+                        MetricArg::Labels(ident, _) => {
+                            return Err(Error::new(ident.span(), "unexpected parameter"))
+                        }
+                        MetricArg::Meta(NestedMeta::Meta(attr)) => attr,
+                        MetricArg::Meta(NestedMeta::Lit(lit)) => {
                             return Err(Error::new(lit.span(), "expected a named parameter"))
                         }
                     };
 
                     let path = attr.path();
-                    if is_struct_level && path.is_ident("subsystem") {
+                    if is_struct_level && path.is_ident("backend") {
+                        result.parse_backend(attr)?
+                    } else if is_struct_level && path.is_ident("local") {
+                        result.parse_local(attr)?
+                    } else if is_struct_level && path.is_ident("subsystem") {
                         result.parse_subsystem(attr)?
                     } else if !is_struct_level && path.is_ident("name") {
                         result.parse_name(attr)?
                     } else if !is_struct_level && path.is_ident("help") {
                         result.parse_help(attr)?
-                    } else if path.is_ident("labels") {
-                        result.parse_labels(attr)?
+                    } else if path.is_ident("labels")
+                        || !is_struct_level && path.is_ident("const_labels")
+                    {
+                        return Err(Error::new(path.span(), "expected a list of labels"));
                     } else if !is_struct_level && path.is_ident("buckets") {
                         result.parse_buckets(attr)?
+                    } else if !is_struct_level && path.is_ident("max_series") {
+                        result.parse_max_series(attr)?
+                    } else if !is_struct_level && path.is_ident("overflow") {
+                        result.parse_overflow(attr)?
+                    } else if !is_struct_level && path.is_ident("ttl_seconds") {
+                        result.parse_ttl_seconds(attr)?
+                    } else if !is_struct_level && path.is_ident("registries") {
+                        result.parse_registries(attr)?
+                    } else if !is_struct_level && path.is_ident("init") {
+                        result.parse_init(attr)?
+                    } else if !is_struct_level && path.is_ident("skip") {
+                        result.parse_skip(attr)?
+                    } else if !is_struct_level && path.is_ident("default") {
+                        result.parse_default(attr)?
+                    } else if !is_struct_level && path.is_ident("const_label") {
+                        result.parse_const_label(attr)?
                     } else {
                         return Err(Error::new(path.span(), "unexpected parameter"));
                     }
@@ -270,6 +1029,10 @@ impl MetricAttrs {
             }
         }
 
+        if let Some(span) = span {
+            result.check_non_metric(span)?;
+        }
+
         if result.help.is_none() {
             result.help = doc;
         }
@@ -277,6 +1040,65 @@ impl MetricAttrs {
         Ok(result)
     }
 
+    /// Check that fields that are not metrics don't have metric options.
+    fn check_non_metric(&self, span: Span) -> Result<()> {
+        if self.default.is_some() && !self.skip {
+            return Err(Error::new(span, "`default` requires `skip` to be set"));
+        }
+
+        if self.skip && self.const_label.is_some() {
+            return Err(Error::new(
+                span,
+                "`skip` can't be combined with `const_label`",
+            ));
+        }
+
+        let has_metric_options = self.name.is_some()
+            || self.help.is_some()
+            || self.labels.is_some()
+            || self.const_labels.is_some()
+            || self.buckets.is_some()
+            || self.max_series.is_some()
+            || self.overflow.is_some()
+            || self.ttl_seconds.is_some()
+            || self.registries.is_some()
+            || self.init.is_some();
+
+        if !self.is_metric() && has_metric_options {
+            return Err(Error::new(
+                span,
+                "fields with `skip` or `const_label` are not metrics, \
+                 and can't have metric options",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check whether the field is a metric, or it holds some other value.
+    fn is_metric(&self) -> bool {
+        !self.skip && self.const_label.is_none()
+    }
+
+    fn parse_local(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("local", meta.path().span(), self.local)?;
+
+        match meta {
+            Meta::Path(_) => self.local = true,
+            _ => return Err(Error::new(meta.span(), "expected a flag: `local`")),
+        }
+
+        Ok(())
+    }
+
+    fn parse_backend(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("backend", meta.path().span(), self.backend.is_some())?;
+
+        self.backend = Some(Self::value_to_string(Self::meta_to_value(meta)?)?);
+
+        Ok(())
+    }
+
     fn parse_subsystem(&mut self, meta: Meta) -> Result<()> {
         Self::check_none("subsystem", meta.path().span(), self.subsystem.is_some())?;
 
@@ -301,23 +1123,61 @@ impl MetricAttrs {
         Ok(())
     }
 
-    fn parse_labels(&mut self, meta: Meta) -> Result<()> {
-        Self::check_none("labels", meta.path().span(), self.labels.is_some())?;
+    fn parse_labels(
+        &mut self,
+        ident: Ident,
+        args: Vec<LabelArg>,
+        is_struct_level: bool,
+    ) -> Result<()> {
+        Self::check_none("labels", ident.span(), self.labels.is_some())?;
 
         let mut labels = Vec::new();
-        for label in Self::meta_to_list(meta)?.nested {
-            let label_span = label.span();
-            let value = Self::value_to_string(Self::nested_meta_to_value(label)?)?;
-            if labels.contains(&value) {
-                return Err(Error::new(label_span, "duplicate label"));
+        for arg in args {
+            if labels.contains(&arg.name) || self.static_labels.iter().any(|(n, _)| *n == arg.name)
+            {
+                return Err(Error::new(arg.span, "duplicate label"));
+            }
+
+            match arg.value {
+                None => labels.push(arg.name),
+                Some(value) if is_struct_level => self.static_labels.push((arg.name, value)),
+                Some(_) => {
+                    return Err(Error::new(
+                        arg.span,
+                        "labels of multidimensional metrics can't have values",
+                    ))
+                }
             }
-            labels.push(value)
         }
         self.labels = Some(labels);
 
         Ok(())
     }
 
+    fn parse_const_labels(&mut self, ident: Ident, args: Vec<LabelArg>) -> Result<()> {
+        Self::check_none("const_labels", ident.span(), self.const_labels.is_some())?;
+
+        let mut labels: Vec<(String, Expr)> = Vec::new();
+        for arg in args {
+            if labels.iter().any(|(name, _)| *name == arg.name) {
+                return Err(Error::new(arg.span, "duplicate label"));
+            }
+
+            match arg.value {
+                Some(value) => labels.push((arg.name, value)),
+                None => {
+                    return Err(Error::new(
+                        arg.span,
+                        "const labels of a field should have values: `name = value`",
+                    ))
+                }
+            }
+        }
+        self.const_labels = Some(labels);
+
+        Ok(())
+    }
+
     fn parse_buckets(&mut self, meta: Meta) -> Result<()> {
         Self::check_none("buckets", meta.path().span(), self.buckets.is_some())?;
 
@@ -330,6 +1190,104 @@ impl MetricAttrs {
         Ok(())
     }
 
+    fn parse_max_series(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("max_series", meta.path().span(), self.max_series.is_some())?;
+
+        self.max_series = Some(Self::value_to_int(Self::meta_to_value(meta)?)?);
+
+        Ok(())
+    }
+
+    fn parse_overflow(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("overflow", meta.path().span(), self.overflow.is_some())?;
+
+        self.overflow = Some(Self::value_to_string(Self::meta_to_value(meta)?)?);
+
+        Ok(())
+    }
+
+    fn parse_ttl_seconds(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none(
+            "ttl_seconds",
+            meta.path().span(),
+            self.ttl_seconds.is_some(),
+        )?;
+
+        self.ttl_seconds = Some(Self::value_to_int(Self::meta_to_value(meta)?)?);
+
+        Ok(())
+    }
+
+    fn parse_registries(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("registries", meta.path().span(), self.registries.is_some())?;
+
+        let mut registries = Vec::new();
+        match meta {
+            Meta::List(list) => {
+                for registry in list.nested {
+                    registries.push(Self::value_to_string(Self::nested_meta_to_value(
+                        registry,
+                    )?)?)
+                }
+            }
+            meta => registries.push(Self::value_to_string(Self::meta_to_value(meta)?)?),
+        }
+        self.registries = Some(registries);
+
+        Ok(())
+    }
+
+    fn parse_init(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("init", meta.path().span(), self.init.is_some())?;
+
+        match Self::meta_to_value(meta)? {
+            Lit::Str(lit) => self.init = Some(lit.parse()?),
+            lit => return Err(Error::new(lit.span(), "expected a path to a function")),
+        }
+
+        Ok(())
+    }
+
+    fn parse_skip(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("skip", meta.path().span(), self.skip)?;
+
+        match meta {
+            Meta::Path(_) => self.skip = true,
+            _ => return Err(Error::new(meta.span(), "expected a flag: `skip`")),
+        }
+
+        Ok(())
+    }
+
+    fn parse_default(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none("default", meta.path().span(), self.default.is_some())?;
+
+        match Self::meta_to_value(meta)? {
+            Lit::Str(lit) => self.default = Some(lit.parse()?),
+            lit => return Err(Error::new(lit.span(), "expected an expression in a string")),
+        }
+
+        Ok(())
+    }
+
+    fn parse_const_label(&mut self, meta: Meta) -> Result<()> {
+        Self::check_none(
+            "const_label",
+            meta.path().span(),
+            self.const_label.is_some(),
+        )?;
+
+        match meta {
+            Meta::Path(_) => self.const_label = Some(None),
+            meta => {
+                let label = Self::value_to_string(Self::meta_to_value(meta)?)?;
+                self.const_label = Some(Some(label));
+            }
+        }
+
+        Ok(())
+    }
+
     fn meta_to_value(meta: Meta) -> Result<Lit> {
         match meta {
             Meta::NameValue(kv) => Ok(kv.lit),
@@ -358,6 +1316,17 @@ impl MetricAttrs {
         }
     }
 
+    fn value_to_int<N>(lit: Lit) -> Result<N>
+    where
+        N: std::str::FromStr,
+        N::Err: std::fmt::Display,
+    {
+        match lit {
+            Lit::Int(i) => i.base10_parse(),
+            _ => Err(Error::new(lit.span(), "expected an integer")),
+        }
+    }
+
     fn value_to_float(lit: Lit) -> Result<f64> {
         match lit {
             Lit::Int(i) => i.base10_parse(),
@@ -374,3 +1343,58 @@ impl MetricAttrs {
         }
     }
 }
+
+/// Parameter of the `#[metric(...)]` attribute.
+enum MetricArg {
+    /// A list of labels. Labels are parsed separately from other
+    /// parameters because their values can be arbitrary expressions.
+    Labels(Ident, Vec<LabelArg>),
+    Meta(NestedMeta),
+}
+
+impl Parse for MetricArg {
+    fn parse(input: ParseStream) -> Result<Self> {
+        let fork = input.fork();
+        if let Ok(ident) = fork.parse::<Ident>() {
+            if (ident == "labels" || ident == "const_labels") && fork.peek(token::Paren) {
+                input.parse::<Ident>()?;
+                let content;
+                parenthesized!(content in input);
+                let labels = content.parse_terminated::<_, Token![,]>(LabelArg::parse)?;
+                return Ok(MetricArg::Labels(ident, labels.into_iter().collect()));
+            }
+        }
+
+        input.parse().map(MetricArg::Meta)
+    }
+}
+
+/// Label name with an optional value: `"name"` or `name = expr`.
+struct LabelArg {
+    name: String,
+    span: Span,
+    value: Option<Expr>,
+}
+
+impl Parse for LabelArg {
+    fn parse(input: ParseStream) -> Result<Self> {
+        if input.peek(LitStr) {
+            let name: LitStr = input.parse()?;
+            Ok(LabelArg {
+                name: name.value(),
+                span: name.span(),
+                value: None,
+            })
+        } else if input.peek(Ident::peek_any) {
+            let name = input.call(Ident::parse_any)?;
+            input.parse::<Token![=]>()?;
+            Ok(LabelArg {
+                name: name.unraw().to_string(),
+                span: name.span(),
+                value: Some(input.parse()?),
+            })
+        } else {
+            Err(input.error("expected a label name or `name = value`"))
+        }
+    }
+}
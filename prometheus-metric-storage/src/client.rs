@@ -0,0 +1,237 @@
+//! Backend for the [`prometheus-client`] crate.
+//!
+//! Storages that use this backend are declared with
+//! `#[metric(backend = "prometheus-client")]`. Their fields are metrics from
+//! the [`prometheus-client`] crate, such as [`Counter`], [`Gauge`],
+//! [`Histogram`] and [`Family`], and the derive macro registers them
+//! in a [`prometheus_client::registry::Registry`] instead of a
+//! [`prometheus::Registry`].
+//!
+//! Apart from that, storages are declared the same way as with the default
+//! backend. Struct-level `subsystem` becomes a registry prefix, and const
+//! labels are attached to every metric via a sub-registry:
+//!
+//! ```
+//! # use prometheus_metric_storage::MetricStorage;
+//! use prometheus_client::encoding::text::encode;
+//! use prometheus_client::metrics::counter::Counter;
+//! use prometheus_client::metrics::family::Family;
+//! use prometheus_client::metrics::histogram::Histogram;
+//! use prometheus_metric_storage::client::HistogramConstructor;
+//!
+//! #[derive(MetricStorage)]
+//! #[metric(backend = "prometheus-client", subsystem = "transport", labels("endpoint"))]
+//! struct Metrics {
+//!     /// Number of requests.
+//!     requests: Family<Vec<(String, String)>, Counter>,
+//!
+//!     /// Request latency.
+//!     #[metric(buckets(0.1, 0.5, 1.0))]
+//!     latency_seconds: Histogram,
+//!
+//!     /// Request latency by method.
+//!     #[metric(buckets(0.1, 0.5, 1.0))]
+//!     method_latency_seconds: Family<Vec<(String, String)>, Histogram, HistogramConstructor>,
+//! }
+//!
+//! let mut registry = prometheus_client::registry::Registry::default();
+//! let metrics = Metrics::new(&mut registry, "0.0.0.0:8080").unwrap();
+//!
+//! metrics
+//!     .requests
+//!     .get_or_create(&vec![("method".to_string(), "GET".to_string())])
+//!     .inc();
+//! metrics.latency_seconds.observe(0.3);
+//!
+//! let mut text = String::new();
+//! encode(&mut text, &registry).unwrap();
+//! assert!(text.contains("# HELP transport_requests Number of requests.\n"));
+//! assert!(text.contains(
+//!     "transport_requests_total{endpoint=\"0.0.0.0:8080\",method=\"GET\"} 1\n"
+//! ));
+//! assert!(text.contains(
+//!     "transport_latency_seconds_bucket{endpoint=\"0.0.0.0:8080\",le=\"0.5\"} 1\n"
+//! ));
+//! ```
+//!
+//! Variable labels are defined by the label set type of a [`Family`],
+//! so field-level `labels` are not supported. Neither are `max_series`,
+//! `ttl_seconds` and struct-level `local`. Also, storages that use this
+//! backend ignore the [runtime configuration], and they can't be used
+//! with a [`StorageRegistry`].
+//!
+//! Note that `prometheus-client` adds the `_total` suffix to counter names
+//! automatically, so counter fields shouldn't have it.
+//!
+//! [`prometheus-client`]: https://docs.rs/prometheus-client
+//! [runtime configuration]: crate::MetricsConfig
+//! [`StorageRegistry`]: crate::StorageRegistry
+
+use crate::{check_const_labels, Result};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::{Family, MetricConstructor};
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::registry::Metric;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+#[doc(hidden)]
+pub use prometheus_client::registry::Registry;
+
+/// Metric storage that registers its metrics in a `prometheus-client`
+/// registry. This is a counterpart of [`crate::MetricStorage`]
+/// for storages declared with `#[metric(backend = "prometheus-client")]`.
+///
+/// Derive macro generates a private inherent `new` function that takes
+/// a registry and values for const labels, and calls
+/// [`from_const_labels`](MetricStorage::from_const_labels).
+pub trait MetricStorage: Sized {
+    /// Get names of const labels for this storage.
+    fn const_labels() -> &'static [&'static str];
+
+    /// Create a new instance of this storage and register all of its metrics
+    /// in the given registry.
+    ///
+    /// Const labels must contain all labels
+    /// from [`const_labels`](MetricStorage::const_labels), and nothing else.
+    fn from_const_labels(
+        registry: &mut Registry,
+        const_labels: HashMap<String, String>,
+    ) -> Result<Self>;
+}
+
+/// Initialize a new `prometheus-client` metric.
+///
+/// Implement this trait to use custom metric types in storages that use
+/// the `prometheus-client` backend.
+pub trait MetricInit: Metric + Clone + Sized {
+    /// Create a new metric.
+    fn init() -> Self;
+}
+
+impl<N, A> MetricInit for Counter<N, A>
+where
+    Self: Metric + Clone + Default,
+{
+    fn init() -> Self {
+        Self::default()
+    }
+}
+
+impl<N, A> MetricInit for Gauge<N, A>
+where
+    Self: Metric + Clone + Default,
+{
+    fn init() -> Self {
+        Self::default()
+    }
+}
+
+impl<S: Clone + Hash + Eq, M: Default> MetricInit for Family<S, M>
+where
+    Self: Metric + Clone,
+{
+    fn init() -> Self {
+        Self::default()
+    }
+}
+
+impl MetricInit for Histogram {
+    fn init() -> Self {
+        <Self as HistMetricInit>::init(prometheus::DEFAULT_BUCKETS.to_vec())
+    }
+}
+
+impl<S: Clone + Hash + Eq> MetricInit for Family<S, Histogram, HistogramConstructor>
+where
+    Self: Metric,
+{
+    fn init() -> Self {
+        <Self as HistMetricInit>::init(prometheus::DEFAULT_BUCKETS.to_vec())
+    }
+}
+
+/// Initialize a new `prometheus-client` histogram with the given buckets.
+pub trait HistMetricInit: Metric + Clone + Sized {
+    /// Create a new histogram.
+    fn init(buckets: Vec<f64>) -> Self;
+}
+
+impl HistMetricInit for Histogram {
+    fn init(buckets: Vec<f64>) -> Self {
+        Histogram::new(buckets)
+    }
+}
+
+impl<S: Clone + Hash + Eq> HistMetricInit for Family<S, Histogram, HistogramConstructor>
+where
+    Self: Metric,
+{
+    fn init(buckets: Vec<f64>) -> Self {
+        Family::new_with_constructor(HistogramConstructor { buckets })
+    }
+}
+
+/// Constructor for histograms in a [`Family`].
+///
+/// Use `Family<S, Histogram, HistogramConstructor>` as a field type
+/// to get a family of histograms with buckets set
+/// via `#[metric(buckets(...))]`.
+#[derive(Clone, Debug)]
+pub struct HistogramConstructor {
+    buckets: Vec<f64>,
+}
+
+impl HistogramConstructor {
+    /// Create a constructor for histograms with the given buckets.
+    pub fn new(buckets: Vec<f64>) -> Self {
+        Self { buckets }
+    }
+}
+
+impl MetricConstructor<Histogram> for HistogramConstructor {
+    fn new_metric(&self) -> Histogram {
+        Histogram::new(self.buckets.iter().copied())
+    }
+}
+
+/// Check const labels and create a sub-registry for a storage.
+///
+/// Static labels are added after the given const labels.
+#[doc(hidden)]
+pub fn sub_registry<'a>(
+    registry: &'a mut Registry,
+    subsystem: &str,
+    expected_labels: &[&str],
+    const_labels: HashMap<String, String>,
+    static_labels: Vec<(&str, String)>,
+) -> Result<&'a mut Registry> {
+    check_const_labels(expected_labels, &const_labels)?;
+
+    // Keep labels in declaration order so that the output is stable.
+    let labels = expected_labels
+        .iter()
+        .map(|label| (label.to_string(), const_labels[*label].clone()))
+        .chain(
+            static_labels
+                .into_iter()
+                .map(|(label, value)| (label.to_string(), value)),
+        )
+        .map(|(label, value)| (Cow::Owned(label), Cow::Owned(value)));
+
+    let registry = registry.sub_registry_with_labels(labels);
+    if subsystem.is_empty() {
+        Ok(registry)
+    } else {
+        Ok(registry.sub_registry_with_prefix(subsystem))
+    }
+}
+
+/// Register a metric in a storage sub-registry.
+#[doc(hidden)]
+pub fn register(registry: &mut Registry, name: &str, help: &str, metric: impl Metric) {
+    // `prometheus-client` adds a dot to help messages by itself.
+    registry.register(name, help.strip_suffix('.').unwrap_or(help), metric)
+}
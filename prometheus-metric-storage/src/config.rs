@@ -0,0 +1,271 @@
+//! Runtime configuration overrides for metric storages.
+
+use crate::Opts;
+use prometheus::core::Collector;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Runtime overrides for metrics created by metric storages.
+///
+/// Metric storages consult the [current config], so operators can retune
+/// histogram buckets, disable specific metrics, or add extra const labels
+/// without rebuilding the binary.
+///
+/// Overrides are looked up by the full metric name, that is, the name
+/// with subsystem prepended (see [`Opts::fq_name`]).
+///
+/// With the `serde` feature enabled, this struct implements `Deserialize`,
+/// so it can be loaded from any format supported by serde,
+/// such as TOML or JSON:
+///
+/// ```toml
+/// [metrics.transport_requests_duration_seconds]
+/// buckets = [0.01, 0.05, 0.1, 0.5, 1.0]
+///
+/// [metrics.transport_inflight]
+/// disabled = true
+///
+/// [metrics.transport_requests_finished.const_labels]
+/// datacenter = "eu-1"
+/// ```
+///
+/// The config can also be loaded from environment variables,
+/// see [`MetricsConfig::from_env`].
+///
+/// Buckets and const labels are applied when a storage is created,
+/// so they only affect storages created after the config was installed
+/// via [`set_metrics_config`]. Disabled metrics, on the other hand,
+/// are checked every time a storage is registered or unregistered.
+/// Thus, a storage should be unregistered with the same config
+/// it was registered with; it's best to install the config once,
+/// before any storages are created.
+///
+/// # Example
+///
+/// ```
+/// # #[cfg(feature = "serde")] {
+/// # use prometheus_metric_storage::{MetricConfig, MetricsConfig};
+/// let config: MetricsConfig = serde_json::from_str(r#"{
+///     "metrics": {
+///         "transport_requests_duration_seconds": {
+///             "buckets": [0.01, 0.05, 0.1]
+///         },
+///         "transport_inflight": {
+///             "disabled": true
+///         }
+///     }
+/// }"#).unwrap();
+///
+/// assert_eq!(
+///     config.buckets("transport_requests_duration_seconds"),
+///     Some(&[0.01, 0.05, 0.1][..]),
+/// );
+/// assert!(!config.is_enabled("transport_inflight"));
+/// assert!(config.is_enabled("transport_requests_duration_seconds"));
+///
+/// let err = serde_json::from_str::<MetricsConfig>(r#"{
+///     "metrics": { "transport_inflight": { "enabled": false } }
+/// }"#);
+/// assert!(err.is_err());
+/// # }
+/// ```
+///
+/// [current config]: metrics_config
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct MetricsConfig {
+    /// Overrides for individual metrics, keyed by full metric name.
+    pub metrics: HashMap<String, MetricConfig>,
+}
+
+/// Runtime overrides for a single metric.
+///
+/// See [`MetricsConfig`] for more info.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize, serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct MetricConfig {
+    /// If set to `true`, the metric will be created, but not registered.
+    pub disabled: bool,
+
+    /// Histogram buckets that override the ones from
+    /// the `#[metric(buckets(...))]` attribute.
+    ///
+    /// This setting only affects histograms and histogram vectors.
+    pub buckets: Option<Vec<f64>>,
+
+    /// Const labels that will be added to the metric.
+    pub const_labels: HashMap<String, String>,
+}
+
+impl MetricsConfig {
+    /// Load a config from environment variables whose names start
+    /// with the given prefix.
+    ///
+    /// Each variable sets a single setting of a single metric. Its name
+    /// consists of the prefix, the full metric name in upper case,
+    /// a double underscore, and the setting name:
+    ///
+    /// - `<PREFIX><METRIC>__DISABLED` is `true` or `false`;
+    /// - `<PREFIX><METRIC>__BUCKETS` is a comma-separated list of numbers;
+    /// - `<PREFIX><METRIC>__CONST_LABELS` is a comma-separated list
+    ///   of `name=value` pairs.
+    ///
+    /// Returns an error if a variable has an unknown setting
+    /// or a malformed value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricsConfig;
+    /// std::env::set_var("METRICS_TRANSPORT_INFLIGHT__DISABLED", "true");
+    /// std::env::set_var("METRICS_TRANSPORT_REQUESTS_DURATION_SECONDS__BUCKETS", "0.01, 0.1, 1");
+    /// std::env::set_var("METRICS_TRANSPORT_REQUESTS_FINISHED__CONST_LABELS", "datacenter=eu-1");
+    ///
+    /// let config = MetricsConfig::from_env("METRICS_").unwrap();
+    ///
+    /// assert!(!config.is_enabled("transport_inflight"));
+    /// assert_eq!(
+    ///     config.buckets("transport_requests_duration_seconds"),
+    ///     Some(&[0.01, 0.1, 1.0][..]),
+    /// );
+    /// assert_eq!(
+    ///     config.metrics["transport_requests_finished"].const_labels["datacenter"],
+    ///     "eu-1",
+    /// );
+    ///
+    /// std::env::set_var("METRICS_TRANSPORT_INFLIGHT__ENABLED", "false");
+    /// assert!(MetricsConfig::from_env("METRICS_").is_err());
+    /// ```
+    pub fn from_env(prefix: &str) -> prometheus::Result<Self> {
+        let mut config = MetricsConfig::default();
+
+        for (var, value) in std::env::vars() {
+            let key = match var.strip_prefix(prefix) {
+                Some(key) => key,
+                None => continue,
+            };
+            let (metric, setting) = key.rsplit_once("__").ok_or_else(|| {
+                prometheus::Error::Msg(format!("{}: setting name is missing", var))
+            })?;
+            let metric = config.metrics.entry(metric.to_lowercase()).or_default();
+            let err = |msg: &str| prometheus::Error::Msg(format!("{}: {}", var, msg));
+
+            match setting {
+                "DISABLED" => {
+                    metric.disabled = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| err("expected true or false"))?;
+                }
+                "BUCKETS" => {
+                    let buckets = value
+                        .split(',')
+                        .map(|bucket| bucket.trim().parse())
+                        .collect::<Result<_, _>>()
+                        .map_err(|_| err("expected comma-separated numbers"))?;
+                    metric.buckets = Some(buckets);
+                }
+                "CONST_LABELS" => {
+                    for label in value.split(',') {
+                        let (name, value) = label
+                            .split_once('=')
+                            .ok_or_else(|| err("expected comma-separated name=value pairs"))?;
+                        metric
+                            .const_labels
+                            .insert(name.trim().to_string(), value.trim().to_string());
+                    }
+                }
+                _ => return Err(err("unknown setting")),
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Find overrides for a metric with the given full name.
+    pub fn get(&self, fq_name: &str) -> Option<&MetricConfig> {
+        self.metrics.get(fq_name)
+    }
+
+    /// Check whether a metric with the given full name is enabled.
+    pub fn is_enabled(&self, fq_name: &str) -> bool {
+        !self.get(fq_name).is_some_and(|config| config.disabled)
+    }
+
+    /// Check whether all metrics reported by the given collector are enabled.
+    pub fn is_collector_enabled(&self, c: &dyn Collector) -> bool {
+        c.desc().iter().all(|desc| self.is_enabled(&desc.fq_name))
+    }
+
+    /// Get overridden histogram buckets for a metric with the given full name.
+    pub fn buckets(&self, fq_name: &str) -> Option<&[f64]> {
+        self.get(fq_name)?.buckets.as_deref()
+    }
+
+    /// Add extra const labels from this config to the given options.
+    pub fn apply(&self, opts: &mut Opts) {
+        if let Some(config) = self.get(&opts.fq_name()) {
+            for (name, value) in &config.const_labels {
+                opts.const_labels.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref METRICS_CONFIG: RwLock<Arc<MetricsConfig>> = Default::default();
+}
+
+/// Get the currently installed metrics config.
+///
+/// If no config was installed, returns an empty one.
+pub fn metrics_config() -> Arc<MetricsConfig> {
+    METRICS_CONFIG.read().unwrap().clone()
+}
+
+/// Install a new metrics config.
+///
+/// Buckets and const labels from the config will be used by all storages
+/// created after this call. Disabled metrics will be skipped by all
+/// subsequent registrations and unregistrations, including those of
+/// storages that were created earlier.
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{set_metrics_config, MetricConfig, MetricsConfig, MetricStorage};
+/// #[derive(MetricStorage)]
+/// #[metric(subsystem = "transport")]
+/// struct Metrics {
+///     /// Number of requests that are currently inflight.
+///     inflight: prometheus::IntGauge,
+///
+///     /// Processing time of each request in seconds.
+///     #[metric(buckets(0.1, 0.5, 1))]
+///     requests_duration_seconds: prometheus::Histogram,
+/// }
+///
+/// let mut config = MetricsConfig::default();
+/// config.metrics.insert(
+///     "transport_inflight".to_string(),
+///     MetricConfig { disabled: true, ..Default::default() },
+/// );
+/// config.metrics.insert(
+///     "transport_requests_duration_seconds".to_string(),
+///     MetricConfig { buckets: Some(vec![0.01, 0.1]), ..Default::default() },
+/// );
+/// set_metrics_config(config);
+///
+/// let registry = prometheus::Registry::new();
+/// let metrics = Metrics::new(&registry).unwrap();
+/// metrics.requests_duration_seconds.observe(0.05);
+///
+/// let families = registry.gather();
+/// assert_eq!(families.len(), 1);
+/// assert_eq!(families[0].get_metric()[0].get_histogram().get_bucket().len(), 2);
+/// ```
+pub fn set_metrics_config(config: MetricsConfig) {
+    *METRICS_CONFIG.write().unwrap() = Arc::new(config);
+}
@@ -0,0 +1,228 @@
+//! Errors returned by metric storages.
+
+use std::fmt::{Display, Formatter};
+
+/// Error returned when creating, looking up or registering
+/// a metric storage.
+///
+/// Unlike a plain [`prometheus::Error`], this error tells which field
+/// of a storage and which metric caused the failure. It converts into
+/// [`prometheus::Error`], so functions that return [`prometheus::Result`]
+/// can still use the `?` operator on storage results.
+///
+/// Exporters and metric types from this crate return
+/// a plain [`prometheus::Error`].
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{Error, MetricStorage, StorageRegistry};
+/// # use std::collections::HashMap;
+/// #[derive(MetricStorage)]
+/// #[metric(labels("endpoint"))]
+/// struct Metrics {
+///     /// Number of processed requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// let registry = StorageRegistry::default();
+///
+/// let err = registry.get_or_create_storage::<Metrics>(HashMap::new()).err().unwrap();
+/// assert!(matches!(err, Error::WrongConstLabelCount { expected: 1, got: 0 }));
+///
+/// fn create(registry: &prometheus::Registry) -> prometheus::Result<Metrics> {
+///     Ok(Metrics::new(registry, "/api")?)
+/// }
+///
+/// let registry = prometheus::Registry::new();
+/// create(&registry).unwrap();
+///
+/// let err = create(&registry).err().unwrap();
+/// assert!(err.to_string().contains("failed to register metric \"requests\" of field `requests`"));
+/// ```
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A const label declared in the storage was not given a value.
+    MissingConstLabel {
+        /// Name of the missing label.
+        label: String,
+    },
+
+    /// Number of given const labels doesn't match
+    /// the number of const labels declared in the storage.
+    WrongConstLabelCount {
+        /// Number of declared const labels.
+        expected: usize,
+        /// Number of given const labels.
+        got: usize,
+    },
+
+    /// A const label is given a value more than once, for example,
+    /// by a storage and by a [child registry](crate::StorageRegistry::child).
+    DuplicateConstLabel {
+        /// Name of the label.
+        label: String,
+    },
+
+    /// Storage with the given const labels was not found
+    /// in a [`StorageRegistry`](crate::StorageRegistry).
+    StorageNotFound {
+        /// Type name of the storage.
+        storage: String,
+    },
+
+    /// Storage doesn't implement an optional operation,
+    /// such as unregistering.
+    Unsupported {
+        /// Type name of the storage.
+        storage: String,
+        /// Name of the operation.
+        operation: String,
+    },
+
+    /// Metric of a storage field couldn't be initialized.
+    FieldInit {
+        /// Name of the field.
+        field: String,
+        /// Full name of the metric.
+        metric: String,
+        /// The underlying error.
+        source: prometheus::Error,
+    },
+
+    /// Metric of a storage field couldn't be registered.
+    Registration {
+        /// Name of the field.
+        field: String,
+        /// Full name of the metric.
+        metric: String,
+        /// The underlying error.
+        source: prometheus::Error,
+    },
+
+    /// Metric of a storage field couldn't be unregistered.
+    Unregistration {
+        /// Name of the field.
+        field: String,
+        /// Full name of the metric.
+        metric: String,
+        /// The underlying error.
+        source: prometheus::Error,
+    },
+
+    /// None of the registries a field should be registered in
+    /// was given to [`register_multi`](crate::MetricStorage::register_multi).
+    UnknownRegistry {
+        /// Name of the field.
+        field: String,
+        /// Names of registries from the field's `registries` option.
+        registries: Vec<String>,
+    },
+
+    /// Several operations have failed, for example, when unregistering
+    /// all metrics of a storage.
+    Multiple(Vec<Error>),
+
+    /// Any other error.
+    Prometheus(prometheus::Error),
+}
+
+/// A specialized result type for metric storages.
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::MissingConstLabel { label } => write!(f, "label {:?} is missing", label),
+            Error::WrongConstLabelCount { expected, got } => write!(
+                f,
+                "invalid number of const labels: expected {}, got {}",
+                expected, got
+            ),
+            Error::DuplicateConstLabel { label } => {
+                write!(f, "label {:?} is set more than once", label)
+            }
+            Error::StorageNotFound { storage } => {
+                write!(f, "metric storage {} not found", storage)
+            }
+            Error::Unsupported { storage, operation } => write!(
+                f,
+                "metric storage {} doesn't support {}",
+                storage, operation
+            ),
+            Error::FieldInit {
+                field,
+                metric,
+                source,
+            } => write!(
+                f,
+                "failed to initialize metric {:?} of field `{}`: {}",
+                metric, field, source
+            ),
+            Error::Registration {
+                field,
+                metric,
+                source,
+            } => write!(
+                f,
+                "failed to register metric {:?} of field `{}`: {}",
+                metric, field, source
+            ),
+            Error::Unregistration {
+                field,
+                metric,
+                source,
+            } => write!(
+                f,
+                "failed to unregister metric {:?} of field `{}`: {}",
+                metric, field, source
+            ),
+            Error::UnknownRegistry { field, registries } => write!(
+                f,
+                "field `{}` should be registered in {:?}, but none of these registries is given",
+                field, registries
+            ),
+            Error::Multiple(errors) => {
+                for (i, err) in errors.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("; ")?;
+                    }
+                    Display::fmt(err, f)?;
+                }
+                Ok(())
+            }
+            Error::Prometheus(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::FieldInit { source, .. }
+            | Error::Registration { source, .. }
+            | Error::Unregistration { source, .. }
+            | Error::Prometheus(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<prometheus::Error> for Error {
+    fn from(err: prometheus::Error) -> Self {
+        Error::Prometheus(err)
+    }
+}
+
+impl From<Error> for prometheus::Error {
+    /// Errors that wrap a [`prometheus::Error`] without adding context
+    /// are unwrapped; all other errors become [`prometheus::Error::Msg`]
+    /// with the same message.
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Prometheus(err) => err,
+            err => prometheus::Error::Msg(err.to_string()),
+        }
+    }
+}
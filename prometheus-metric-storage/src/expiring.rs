@@ -0,0 +1,174 @@
+//! Expiry of stale series for multidimensional metrics.
+
+use prometheus::core::{Collector, Desc, MetricVec, MetricVecBuilder};
+use prometheus::proto::MetricFamily;
+use prometheus::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A wrapper for [`MetricVec`] that removes series which
+/// were not touched for a given amount of time.
+///
+/// A series is touched every time it's looked up through this wrapper.
+/// Stale series are removed when metrics are collected. This makes
+/// the wrapper suitable for tracking transient entities, such as orders
+/// or connections, in long-running services.
+///
+/// Note that holding on to a metric returned from a lookup and updating
+/// it later does not count as touching it. Look up the metric
+/// every time it's updated.
+///
+/// This wrapper is created by the derive macro when a field has
+/// the `ttl_seconds` option:
+///
+/// ```
+/// # use prometheus_metric_storage::{ExpiringVec, MetricStorage};
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of fills by order.
+///     #[metric(labels("order_uid"), ttl_seconds = 3600)]
+///     fills: ExpiringVec<prometheus::IntCounterVec>,
+/// }
+/// ```
+///
+/// Here's how expiry works:
+///
+/// ```
+/// # use prometheus_metric_storage::{ExpiringVec, MetricInit};
+/// # use std::time::Duration;
+/// use prometheus::core::Collector;
+///
+/// let opts = prometheus::Opts::new("fills", "Number of fills by order.")
+///     .variable_label("order_uid");
+/// let fills: ExpiringVec<prometheus::IntCounterVec> =
+///     ExpiringVec::new(MetricInit::init(opts).unwrap(), Duration::from_millis(50));
+///
+/// fills.with_label_values(&["0x01"]).inc();
+/// assert_eq!(fills.collect()[0].get_metric().len(), 1);
+///
+/// std::thread::sleep(Duration::from_millis(100));
+/// assert_eq!(fills.collect()[0].get_metric().len(), 0);
+/// ```
+///
+/// This wrapper doesn't implement [`MetricInit`], because metric options
+/// don't carry the TTL. Outside of storages, create the underlying
+/// collector and wrap it via [`ExpiringVec::new`], as shown above.
+///
+/// [`MetricInit`]: crate::MetricInit
+#[derive(Clone, Debug)]
+pub struct ExpiringVec<V> {
+    /// The underlying collector.
+    inner: V,
+
+    /// State shared between clones of this collector.
+    state: Arc<ExpiringVecState>,
+}
+
+#[derive(Debug)]
+struct ExpiringVecState {
+    /// Time after which an untouched series is removed.
+    ttl: Duration,
+
+    /// Label combinations and the time they were last touched.
+    series: Mutex<HashMap<Vec<String>, Instant>>,
+}
+
+impl<P: MetricVecBuilder> ExpiringVec<MetricVec<P>> {
+    /// Wrap the given collector.
+    pub fn new(inner: MetricVec<P>, ttl: Duration) -> Self {
+        Self {
+            inner,
+            state: Arc::new(ExpiringVecState {
+                ttl,
+                series: Default::default(),
+            }),
+        }
+    }
+
+    /// Return a reference to the underlying collector.
+    ///
+    /// Series looked up via the underlying collector are never removed.
+    pub fn inner(&self) -> &MetricVec<P> {
+        &self.inner
+    }
+
+    /// Get the time after which an untouched series is removed.
+    pub fn ttl(&self) -> Duration {
+        self.state.ttl
+    }
+
+    /// Return the metric with the given label values and mark it as touched.
+    ///
+    /// See [`MetricVec::get_metric_with_label_values`] for more info.
+    pub fn get_metric_with_label_values(&self, vals: &[&str]) -> Result<P::M> {
+        let mut series = self.state.series.lock().unwrap();
+
+        let metric = self.inner.get_metric_with_label_values(vals)?;
+
+        let key = vals.iter().map(|&val| val.to_string()).collect();
+        series.insert(key, Instant::now());
+
+        Ok(metric)
+    }
+
+    /// Same as [`get_metric_with_label_values`], but panics on error.
+    ///
+    /// [`get_metric_with_label_values`]: ExpiringVec::get_metric_with_label_values
+    pub fn with_label_values(&self, vals: &[&str]) -> P::M {
+        self.get_metric_with_label_values(vals)
+            .unwrap_or_else(|err| panic!("error {:?} when getting metric", err))
+    }
+
+    /// Remove the metric with the given label values.
+    ///
+    /// See [`MetricVec::remove_label_values`] for more info.
+    pub fn remove_label_values(&self, vals: &[&str]) -> Result<()> {
+        let mut series = self.state.series.lock().unwrap();
+
+        self.inner.remove_label_values(vals)?;
+
+        let key: Vec<_> = vals.iter().map(|&val| val.to_string()).collect();
+        series.remove(&key);
+
+        Ok(())
+    }
+
+    /// Remove all metrics.
+    pub fn reset(&self) {
+        let mut series = self.state.series.lock().unwrap();
+
+        self.inner.reset();
+        series.clear();
+    }
+
+    /// Remove all series that were not touched within the TTL.
+    ///
+    /// This function is called automatically when metrics are collected.
+    pub fn remove_expired(&self) {
+        let mut series = self.state.series.lock().unwrap();
+
+        let now = Instant::now();
+        series.retain(|key, touched| {
+            if now.duration_since(*touched) < self.state.ttl {
+                return true;
+            }
+
+            let vals: Vec<_> = key.iter().map(String::as_str).collect();
+            // The series might've been removed via the underlying collector.
+            let _ = self.inner.remove_label_values(&vals);
+            false
+        });
+    }
+}
+
+impl<P: MetricVecBuilder> Collector for ExpiringVec<MetricVec<P>> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.inner.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        self.remove_expired();
+        self.inner.collect()
+    }
+}
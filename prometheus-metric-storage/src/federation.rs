@@ -0,0 +1,667 @@
+//! Parser for the text exposition format and a collector
+//! that re-exposes parsed metrics.
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::{Bucket, LabelPair, Metric, MetricFamily, MetricType, Quantile};
+use prometheus::{Error, Gauge, Opts, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Parse metrics in the Prometheus text exposition format
+/// or in the OpenMetrics text format.
+///
+/// OpenMetrics is detected by the `# EOF` marker. OpenMetrics types
+/// that have no counterpart in Prometheus are converted: `info`
+/// and `stateset` metrics become gauges, `gaugehistogram` metrics become
+/// histograms, and `unknown` metrics become untyped. Exemplars
+/// and `_created` samples are ignored.
+///
+/// Labels of each metric are sorted by name, and families without
+/// samples are skipped, so that the result can be passed
+/// to any [`prometheus::Encoder`].
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::parse_text;
+/// let families = parse_text(
+///     "# HELP requests Number of requests.\n\
+///      ## TYPE requests counter\n\
+///      requests{endpoint=\"/api\"} 10\n",
+/// )
+/// .unwrap();
+///
+/// assert_eq!(families[0].get_name(), "requests");
+/// assert_eq!(families[0].get_metric()[0].get_counter().get_value(), 10.0);
+/// ```
+///
+/// In OpenMetrics, `_created` samples and exemplars are dropped,
+/// and histograms keep their finite buckets:
+///
+/// ```
+/// # use prometheus_metric_storage::parse_text;
+/// let families = parse_text(r#"
+///     ## TYPE request_duration_seconds histogram
+///     ## UNIT request_duration_seconds seconds
+///     request_duration_seconds_bucket{le="0.1"} 2 # {trace_id="3f2a"} 0.05 1700000000.5
+///     request_duration_seconds_bucket{le="1.0"} 3
+///     request_duration_seconds_bucket{le="+Inf"} 4
+///     request_duration_seconds_sum 4.2
+///     request_duration_seconds_count 4
+///     request_duration_seconds_created 1700000000.0
+///     ## EOF
+/// "#)
+/// .unwrap();
+///
+/// assert_eq!(families.len(), 1);
+/// assert_eq!(families[0].get_name(), "request_duration_seconds");
+///
+/// let metric = &families[0].get_metric()[0];
+/// assert!(metric.get_label().is_empty());
+/// assert!(!metric.has_timestamp_ms());
+///
+/// let histogram = metric.get_histogram();
+/// assert_eq!(histogram.get_sample_count(), 4);
+/// assert_eq!(histogram.get_sample_sum(), 4.2);
+/// let buckets: Vec<_> = histogram
+///     .get_bucket()
+///     .iter()
+///     .map(|b| (b.get_upper_bound(), b.get_cumulative_count()))
+///     .collect();
+/// assert_eq!(buckets, [(0.1, 2), (1.0, 3)]);
+/// ```
+///
+/// Summary quantiles are collected into a single metric,
+/// and label values are unescaped:
+///
+/// ```
+/// # use prometheus_metric_storage::parse_text;
+/// let families = parse_text(r#"
+///     ## TYPE rpc_duration_seconds summary
+///     rpc_duration_seconds{method="say \"hi\"\nbye \\o/",quantile="0.5"} 0.2
+///     rpc_duration_seconds{method="say \"hi\"\nbye \\o/",quantile="0.9"} 0.7
+///     rpc_duration_seconds_sum{method="say \"hi\"\nbye \\o/"} 12
+///     rpc_duration_seconds_count{method="say \"hi\"\nbye \\o/"} 30
+/// "#)
+/// .unwrap();
+///
+/// assert_eq!(families.len(), 1);
+/// let metric = &families[0].get_metric()[0];
+///
+/// let labels = metric.get_label();
+/// assert_eq!(labels.len(), 1);
+/// assert_eq!(labels[0].get_name(), "method");
+/// assert_eq!(labels[0].get_value(), "say \"hi\"\nbye \\o/");
+///
+/// let summary = metric.get_summary();
+/// assert_eq!(summary.get_sample_count(), 30);
+/// assert_eq!(summary.get_sample_sum(), 12.0);
+/// let quantiles: Vec<_> = summary
+///     .get_quantile()
+///     .iter()
+///     .map(|q| (q.get_quantile(), q.get_value()))
+///     .collect();
+/// assert_eq!(quantiles, [(0.5, 0.2), (0.9, 0.7)]);
+/// ```
+pub fn parse_text(text: &str) -> Result<Vec<MetricFamily>> {
+    parse(text, &[])
+}
+
+/// A collector that re-exposes metrics parsed from the text
+/// exposition format.
+///
+/// This collector is useful for sidecars and aggregators that scrape
+/// child processes and expose their metrics alongside their own
+/// storages. Register it in a [`StorageRegistry`], then feed it
+/// with scraped text via [`update`]. Every update replaces
+/// all previously parsed metrics.
+///
+/// Const labels given to the collector are added to every parsed metric,
+/// replacing labels with the same name. Use them to tell apart metrics
+/// from different children. The collector also exposes
+/// the `federation_last_update_timestamp_seconds` gauge with these
+/// const labels, which holds time of the last successful update.
+///
+/// Families with the same name as metrics from other collectors
+/// are merged by the registry, so parsed metrics must not clash
+/// with local ones.
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{FederatedCollector, StorageRegistry};
+/// # use std::collections::HashMap;
+/// let registry = StorageRegistry::default();
+///
+/// let mut const_labels = HashMap::new();
+/// const_labels.insert("child".to_string(), "worker-1".to_string());
+/// let collector = FederatedCollector::new(const_labels).unwrap();
+/// registry.register(Box::new(collector.clone())).unwrap();
+///
+/// // Text scraped from the child's metrics endpoint. The `child` label
+/// // is replaced with the one from the collector's const labels.
+/// collector
+///     .update(
+///         "# HELP jobs_total Number of processed jobs.\n\
+///          ## TYPE jobs_total counter\n\
+///          jobs_total{child=\"unknown\",queue=\"default\"} 3\n",
+///     )
+///     .unwrap();
+///
+/// let families = registry.gather();
+/// let jobs = families.iter().find(|f| f.get_name() == "jobs_total").unwrap();
+/// let labels = jobs.get_metric()[0].get_label();
+/// assert_eq!(labels.len(), 2);
+/// assert_eq!(labels[0].get_name(), "child");
+/// assert_eq!(labels[0].get_value(), "worker-1");
+/// assert_eq!(labels[1].get_name(), "queue");
+/// ```
+///
+/// [`StorageRegistry`]: crate::StorageRegistry
+/// [`update`]: FederatedCollector::update
+#[derive(Clone, Debug)]
+pub struct FederatedCollector {
+    const_labels: Vec<(String, String)>,
+    last_update: Gauge,
+    families: Arc<Mutex<Vec<MetricFamily>>>,
+}
+
+impl FederatedCollector {
+    /// Create a new collector that will add the given const labels
+    /// to every parsed metric.
+    pub fn new(const_labels: HashMap<String, String>) -> Result<Self> {
+        let last_update = Gauge::with_opts(
+            Opts::new(
+                "federation_last_update_timestamp_seconds",
+                "Unix time of the last update of federated metrics.",
+            )
+            .const_labels(const_labels.clone()),
+        )?;
+
+        let mut const_labels: Vec<_> = const_labels.into_iter().collect();
+        const_labels.sort_unstable();
+
+        Ok(Self {
+            const_labels,
+            last_update,
+            families: Default::default(),
+        })
+    }
+
+    /// Replace all metrics in this collector with ones parsed from
+    /// the given text. See [`parse_text`] for supported formats.
+    ///
+    /// If the text can't be parsed, previously parsed metrics are kept.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::FederatedCollector;
+    /// # use prometheus::core::Collector;
+    /// # use std::collections::HashMap;
+    /// let collector = FederatedCollector::new(HashMap::new()).unwrap();
+    /// collector.update("jobs_total 3\n").unwrap();
+    ///
+    /// let err = collector.update("jobs_total{queue=\"default\" 4\n").unwrap_err();
+    /// assert!(matches!(err, prometheus::Error::Msg(msg) if msg.starts_with("line 1:")));
+    ///
+    /// let families = collector.collect();
+    /// let jobs = families.iter().find(|f| f.get_name() == "jobs_total").unwrap();
+    /// assert_eq!(jobs.get_metric()[0].get_untyped().get_value(), 3.0);
+    /// ```
+    pub fn update(&self, text: &str) -> Result<()> {
+        let families = parse(text, &self.const_labels)?;
+        *self.families.lock().unwrap() = families;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_update.set(now.as_secs_f64());
+
+        Ok(())
+    }
+
+    /// Remove all previously parsed metrics.
+    pub fn clear(&self) {
+        self.families.lock().unwrap().clear();
+    }
+}
+
+impl Collector for FederatedCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.last_update.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.families.lock().unwrap().clone();
+        families.extend(self.last_update.collect());
+        families
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Type {
+    Counter,
+    Gauge,
+    Histogram,
+    GaugeHistogram,
+    Summary,
+    Info,
+    StateSet,
+    Untyped,
+}
+
+#[derive(Default)]
+struct Series {
+    labels: Vec<(String, String)>,
+    value: Option<f64>,
+    sum: Option<f64>,
+    count: Option<f64>,
+    buckets: Vec<(f64, f64)>,
+    quantiles: Vec<(f64, f64)>,
+    timestamp_ms: Option<i64>,
+}
+
+struct Family {
+    name: String,
+    help: String,
+    ty: Type,
+    series: Vec<Series>,
+    series_by_labels: HashMap<Vec<(String, String)>, usize>,
+}
+
+struct Parser<'a> {
+    open_metrics: bool,
+    const_labels: &'a [(String, String)],
+    families: Vec<Family>,
+    families_by_name: HashMap<String, usize>,
+}
+
+fn parse(text: &str, const_labels: &[(String, String)]) -> Result<Vec<MetricFamily>> {
+    let mut parser = Parser {
+        open_metrics: text.lines().any(|line| line.trim_end() == "# EOF"),
+        const_labels,
+        families: Vec::new(),
+        families_by_name: HashMap::new(),
+    };
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if parser.open_metrics && line == "# EOF" {
+            break;
+        }
+        parser
+            .line(line)
+            .map_err(|err| Error::Msg(format!("line {}: {}", i + 1, err)))?;
+    }
+
+    Ok(parser.finish())
+}
+
+impl<'a> Parser<'a> {
+    fn line(&mut self, line: &str) -> std::result::Result<(), String> {
+        if line.is_empty() {
+            Ok(())
+        } else if let Some(comment) = line.strip_prefix('#') {
+            self.comment(comment.trim_start())
+        } else {
+            self.sample(line)
+        }
+    }
+
+    fn comment(&mut self, comment: &str) -> std::result::Result<(), String> {
+        let (keyword, rest) = split_word(comment);
+        let (name, rest) = split_word(rest);
+
+        match keyword {
+            "HELP" => {
+                let help = unescape(rest);
+                self.family(name).help = help;
+            }
+            "TYPE" => {
+                let ty = match rest.trim() {
+                    "counter" => Type::Counter,
+                    "gauge" => Type::Gauge,
+                    "histogram" => Type::Histogram,
+                    "gaugehistogram" => Type::GaugeHistogram,
+                    "summary" => Type::Summary,
+                    "info" => Type::Info,
+                    "stateset" => Type::StateSet,
+                    "untyped" | "unknown" => Type::Untyped,
+                    ty => return Err(format!("unknown metric type {:?}", ty)),
+                };
+                self.family(name).ty = ty;
+            }
+            // Other comments, including OpenMetrics `UNIT`, are ignored.
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn sample(&mut self, line: &str) -> std::result::Result<(), String> {
+        let name_end = line
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == ':'))
+            .unwrap_or(line.len());
+        let (name, rest) = line.split_at(name_end);
+        if name.is_empty() {
+            return Err("expected a metric name".to_string());
+        }
+
+        let (mut labels, rest) = if let Some(rest) = rest.trim_start().strip_prefix('{') {
+            parse_labels(rest)?
+        } else {
+            (Vec::new(), rest)
+        };
+
+        let mut tokens = rest.split_whitespace();
+        let value = parse_float(tokens.next().ok_or("expected a sample value")?)?;
+        let timestamp_ms = match tokens.next() {
+            // OpenMetrics exemplars start with a `#`.
+            None | Some("#") => None,
+            Some(timestamp) if self.open_metrics => {
+                Some((parse_float(timestamp)? * 1000.0).round() as i64)
+            }
+            Some(timestamp) => Some(
+                timestamp
+                    .parse()
+                    .map_err(|_| format!("invalid timestamp {:?}", timestamp))?,
+            ),
+        };
+
+        let (family, suffix) = self.resolve(name);
+        let ty = self.families[family].ty;
+
+        let mut take_label = |label: &str| -> std::result::Result<f64, String> {
+            let index = labels
+                .iter()
+                .position(|(name, _)| name == label)
+                .ok_or_else(|| format!("{} is missing the {:?} label", name, label))?;
+            parse_float(&labels.remove(index).1)
+        };
+
+        enum Field {
+            Value,
+            Sum,
+            Count,
+            Bucket(f64),
+            Quantile(f64),
+        }
+
+        let field = match (ty, suffix) {
+            (_, "_created") => return Ok(()),
+            (Type::Histogram, "_bucket") | (Type::GaugeHistogram, "_bucket") => {
+                Field::Bucket(take_label("le")?)
+            }
+            (Type::Histogram, "_sum") | (Type::GaugeHistogram, "_gsum") => Field::Sum,
+            (Type::Histogram, "_count") | (Type::GaugeHistogram, "_gcount") => Field::Count,
+            (Type::Summary, "_sum") => Field::Sum,
+            (Type::Summary, "_count") => Field::Count,
+            (Type::Summary, "") => Field::Quantile(take_label("quantile")?),
+            (Type::Histogram, _) | (Type::GaugeHistogram, _) | (Type::Summary, _) => {
+                return Err(format!("unexpected sample {} for a {:?}", name, ty))
+            }
+            _ => Field::Value,
+        };
+
+        for (name, value) in self.const_labels {
+            labels.retain(|(label, _)| label != name);
+            labels.push((name.clone(), value.clone()));
+        }
+        labels.sort_unstable();
+
+        let series = self.series(family, labels);
+        match field {
+            Field::Value => series.value = Some(value),
+            Field::Sum => series.sum = Some(value),
+            Field::Count => series.count = Some(value),
+            Field::Bucket(le) => series.buckets.push((le, value)),
+            Field::Quantile(quantile) => series.quantiles.push((quantile, value)),
+        }
+        if timestamp_ms.is_some() {
+            series.timestamp_ms = timestamp_ms;
+        }
+
+        Ok(())
+    }
+
+    /// Find family for a sample name, returning the family index
+    /// and the suffix of the sample name.
+    fn resolve<'n>(&mut self, name: &'n str) -> (usize, &'n str) {
+        const SUFFIXES: &[&str] = &[
+            "_bucket", "_sum", "_count", "_gsum", "_gcount", "_total", "_created", "_info",
+        ];
+
+        for suffix in SUFFIXES {
+            let base = match name.strip_suffix(suffix) {
+                Some(base) => base,
+                None => continue,
+            };
+            let &family = match self.families_by_name.get(base) {
+                Some(family) => family,
+                None => continue,
+            };
+            let accepts = match self.families[family].ty {
+                Type::Histogram => ["_bucket", "_sum", "_count", "_created"].contains(suffix),
+                Type::GaugeHistogram => {
+                    ["_bucket", "_gsum", "_gcount", "_created"].contains(suffix)
+                }
+                Type::Summary => ["_sum", "_count", "_created"].contains(suffix),
+                Type::Counter => ["_total", "_created"].contains(suffix),
+                Type::Info => *suffix == "_info",
+                _ => false,
+            };
+            if accepts {
+                return (family, suffix);
+            }
+        }
+
+        self.family(name);
+        (self.families_by_name[name], "")
+    }
+
+    fn family(&mut self, name: &str) -> &mut Family {
+        let families = &mut self.families;
+        let index = *self
+            .families_by_name
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                families.push(Family {
+                    name: name.to_string(),
+                    help: String::new(),
+                    ty: Type::Untyped,
+                    series: Vec::new(),
+                    series_by_labels: HashMap::new(),
+                });
+                families.len() - 1
+            });
+        &mut self.families[index]
+    }
+
+    fn series(&mut self, family: usize, labels: Vec<(String, String)>) -> &mut Series {
+        let family = &mut self.families[family];
+        let series = &mut family.series;
+        let index = *family
+            .series_by_labels
+            .entry(labels.clone())
+            .or_insert_with(|| {
+                series.push(Series {
+                    labels,
+                    ..Series::default()
+                });
+                series.len() - 1
+            });
+        &mut family.series[index]
+    }
+
+    fn finish(self) -> Vec<MetricFamily> {
+        let open_metrics = self.open_metrics;
+        self.families
+            .into_iter()
+            .filter(|family| !family.series.is_empty())
+            .map(|family| convert(family, open_metrics))
+            .collect()
+    }
+}
+
+fn convert(family: Family, open_metrics: bool) -> MetricFamily {
+    let mut result = MetricFamily::default();
+
+    let (name, ty) = match family.ty {
+        Type::Counter if open_metrics && !family.name.ends_with("_total") => {
+            (format!("{}_total", family.name), MetricType::COUNTER)
+        }
+        Type::Counter => (family.name, MetricType::COUNTER),
+        Type::Gauge | Type::StateSet => (family.name, MetricType::GAUGE),
+        Type::Info => (format!("{}_info", family.name), MetricType::GAUGE),
+        Type::Histogram | Type::GaugeHistogram => (family.name, MetricType::HISTOGRAM),
+        Type::Summary => (family.name, MetricType::SUMMARY),
+        Type::Untyped => (family.name, MetricType::UNTYPED),
+    };
+    result.set_name(name);
+    result.set_help(family.help);
+    result.set_field_type(ty);
+
+    for series in family.series {
+        let mut metric = Metric::default();
+
+        for (name, value) in series.labels {
+            let mut label = LabelPair::default();
+            label.set_name(name);
+            label.set_value(value);
+            metric.mut_label().push(label);
+        }
+
+        if let Some(timestamp_ms) = series.timestamp_ms {
+            metric.set_timestamp_ms(timestamp_ms);
+        }
+
+        let value = series.value.unwrap_or_default();
+        match ty {
+            MetricType::COUNTER => metric.mut_counter().set_value(value),
+            MetricType::GAUGE => metric.mut_gauge().set_value(value),
+            MetricType::UNTYPED => metric.mut_untyped().set_value(value),
+            MetricType::HISTOGRAM => {
+                let mut buckets = series.buckets;
+                buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+                // The `+Inf` bucket is implied by the sample count.
+                let inf = buckets.iter().find(|(le, _)| le.is_infinite());
+                let count = series.count.or_else(|| inf.map(|(_, count)| *count));
+
+                let histogram = metric.mut_histogram();
+                histogram.set_sample_count(count.unwrap_or_default() as u64);
+                histogram.set_sample_sum(series.sum.unwrap_or_default());
+                for (le, count) in buckets.into_iter().filter(|(le, _)| le.is_finite()) {
+                    let mut bucket = Bucket::default();
+                    bucket.set_upper_bound(le);
+                    bucket.set_cumulative_count(count as u64);
+                    histogram.mut_bucket().push(bucket);
+                }
+            }
+            MetricType::SUMMARY => {
+                let mut quantiles = series.quantiles;
+                quantiles.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+                let summary = metric.mut_summary();
+                summary.set_sample_count(series.count.unwrap_or_default() as u64);
+                summary.set_sample_sum(series.sum.unwrap_or_default());
+                for (q, value) in quantiles {
+                    let mut quantile = Quantile::default();
+                    quantile.set_quantile(q);
+                    quantile.set_value(value);
+                    summary.mut_quantile().push(quantile);
+                }
+            }
+        }
+
+        result.mut_metric().push(metric);
+    }
+
+    result
+}
+
+/// Parse labels after the opening brace, returning them
+/// and the rest of the line after the closing brace.
+#[allow(clippy::type_complexity)]
+fn parse_labels(mut rest: &str) -> std::result::Result<(Vec<(String, String)>, &str), String> {
+    let mut labels = Vec::new();
+
+    loop {
+        rest = rest.trim_start();
+        if let Some(rest) = rest.strip_prefix('}') {
+            return Ok((labels, rest));
+        }
+
+        let (name, value) = rest
+            .split_once('=')
+            .ok_or("expected a label name followed by `=`")?;
+        let name = name.trim();
+        let value = value
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or("expected a quoted label value")?;
+
+        let mut result = String::new();
+        let mut chars = value.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((i, '"')) => break i,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => result.push('\n'),
+                    Some((_, c)) => result.push(c),
+                    None => return Err("unterminated label value".to_string()),
+                },
+                Some((_, c)) => result.push(c),
+                None => return Err("unterminated label value".to_string()),
+            }
+        };
+
+        labels.push((name.to_string(), result));
+
+        rest = value[end + 1..].trim_start();
+        if let Some(next) = rest.strip_prefix(',') {
+            rest = next;
+        } else if !rest.starts_with('}') {
+            return Err("expected `,` or `}` after a label value".to_string());
+        }
+    }
+}
+
+fn parse_float(value: &str) -> std::result::Result<f64, String> {
+    match value {
+        "+Inf" | "Inf" => Ok(f64::INFINITY),
+        "-Inf" => Ok(f64::NEG_INFINITY),
+        "NaN" => Ok(f64::NAN),
+        value => value
+            .parse()
+            .map_err(|_| format!("invalid number {:?}", value)),
+    }
+}
+
+fn split_word(s: &str) -> (&str, &str) {
+    match s.split_once(char::is_whitespace) {
+        Some((word, rest)) => (word, rest.trim_start()),
+        None => (s, ""),
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                result.push('\n');
+                chars.next();
+            }
+            ('\\', Some(c @ '\\')) | ('\\', Some(c @ '"')) => {
+                result.push(c);
+                chars.next();
+            }
+            (c, _) => result.push(c),
+        }
+    }
+    result
+}
@@ -0,0 +1,156 @@
+//! Collector for several fields that share a metric name.
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+/// Builder for [`MetricGroup`].
+#[doc(hidden)]
+pub struct MetricGroupBuilder {
+    labels: Vec<String>,
+    members: Vec<Box<dyn Collector>>,
+}
+
+impl MetricGroupBuilder {
+    /// Create a group whose members differ in values of the given
+    /// const labels.
+    pub fn new(labels: &[&str]) -> Self {
+        Self {
+            labels: labels.iter().map(|label| label.to_string()).collect(),
+            members: Vec::new(),
+        }
+    }
+
+    /// Add a collector to the group.
+    pub fn push<C: Collector + Clone + 'static>(&mut self, collector: &C) {
+        self.members.push(Box::new(collector.clone()));
+    }
+
+    /// Full name of the group's metric.
+    pub fn metric_name(&self) -> String {
+        self.members
+            .iter()
+            .flat_map(|member| member.desc())
+            .map(|desc| desc.fq_name.clone())
+            .next()
+            .unwrap_or_default()
+    }
+
+    /// Check that all members report the same metric with different
+    /// label values, and build the group.
+    ///
+    /// Returns `None` if the group has no members.
+    pub fn build(self) -> Result<Option<MetricGroup>> {
+        let mut desc: Option<Desc> = None;
+        let mut seen = HashSet::new();
+
+        for member in &self.members {
+            let member_desc = match member.desc().as_slice() {
+                [member_desc] => *member_desc,
+                _ => {
+                    return Err(Error::Msg(format!(
+                        "metric {:?} has a field that reports more than one metric",
+                        self.metric_name()
+                    )))
+                }
+            };
+
+            let mut const_labels = HashMap::new();
+            let mut values = vec![""; self.labels.len()];
+            for pair in &member_desc.const_label_pairs {
+                match self
+                    .labels
+                    .iter()
+                    .position(|label| label == pair.get_name())
+                {
+                    Some(i) => values[i] = pair.get_value(),
+                    None => {
+                        const_labels
+                            .insert(pair.get_name().to_string(), pair.get_value().to_string());
+                    }
+                }
+            }
+
+            if !seen.insert(values) {
+                return Err(Error::Msg(format!(
+                    "metric {:?} has several fields with the same values of labels {:?}",
+                    member_desc.fq_name, self.labels
+                )));
+            }
+
+            let mut variable_labels = self.labels.clone();
+            variable_labels.extend(member_desc.variable_labels.iter().cloned());
+
+            let member_desc = Desc::new(
+                member_desc.fq_name.clone(),
+                member_desc.help.clone(),
+                variable_labels,
+                const_labels,
+            )?;
+
+            match &desc {
+                None => desc = Some(member_desc),
+                Some(desc)
+                    if desc.id == member_desc.id && desc.dim_hash == member_desc.dim_hash => {}
+                Some(desc) => {
+                    return Err(Error::Msg(format!(
+                        "fields of metric {:?} have inconsistent help messages or labels",
+                        desc.fq_name
+                    )))
+                }
+            }
+        }
+
+        Ok(desc.map(|desc| MetricGroup {
+            inner: Arc::new(MetricGroupInner {
+                desc,
+                members: self.members,
+            }),
+        }))
+    }
+}
+
+/// Collector that combines several fields of a storage into a single
+/// metric family.
+///
+/// Fields of a group share a metric name and differ in values
+/// of their field-level const labels. The group reports a single [`Desc`]
+/// in which these labels are variable, so registries see one metric
+/// rather than several metrics with the same name.
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct MetricGroup {
+    inner: Arc<MetricGroupInner>,
+}
+
+struct MetricGroupInner {
+    desc: Desc,
+    members: Vec<Box<dyn Collector>>,
+}
+
+impl Collector for MetricGroup {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.inner.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut result: Option<MetricFamily> = None;
+
+        for member in &self.inner.members {
+            for mut family in member.collect() {
+                match &mut result {
+                    Some(result) => {
+                        for metric in family.take_metric() {
+                            result.mut_metric().push(metric);
+                        }
+                    }
+                    None => result = Some(family),
+                }
+            }
+        }
+
+        result.into_iter().collect()
+    }
+}
@@ -0,0 +1,230 @@
+//! Encoder for the JSON exposition format.
+
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use prometheus::{Encoder, Error, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// The JSON format is registered with this content type.
+pub const JSON_FORMAT: &str = "application/json";
+
+/// An [`Encoder`] that renders metric families as JSON.
+///
+/// This format is handy for debug endpoints, and for tools that
+/// don't understand the Prometheus text format. Output is an array
+/// of metric families:
+///
+/// ```json
+/// [
+///   {
+///     "name": "requests_duration_seconds",
+///     "help": "Request latency.",
+///     "type": "histogram",
+///     "metrics": [
+///       {
+///         "labels": {"endpoint": "/api"},
+///         "count": 1,
+///         "sum": 0.3,
+///         "buckets": [{"le": 0.1, "count": 0}, {"le": 0.5, "count": 1}]
+///       }
+///     ]
+///   }
+/// ]
+/// ```
+///
+/// Counters, gauges and untyped metrics have a `value` field instead
+/// of `count`, `sum` and `buckets`; summaries have `count`, `sum`
+/// and `quantiles`. Bucket counts are cumulative, just like in the
+/// text format. Values that can't be represented in JSON,
+/// such as `NaN` and infinities, are rendered as `null`.
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{JsonEncoder, MetricStorage, StorageRegistry};
+/// use prometheus::Encoder;
+///
+/// #[derive(MetricStorage)]
+/// #[metric(labels("endpoint"))]
+/// struct Metrics {
+///     /// Number of processed requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// let registry = StorageRegistry::default();
+/// Metrics::instance(&registry, "/api").unwrap().requests.inc();
+///
+/// let mut buffer = Vec::new();
+/// JsonEncoder::new().encode(&registry.gather(), &mut buffer).unwrap();
+///
+/// assert_eq!(
+///     String::from_utf8(buffer).unwrap(),
+///     r#"[{"name":"requests","help":"Number of processed requests.","type":"counter","metrics":[{"labels":{"endpoint":"/api"},"value":1.0}]}]"#
+/// );
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonEncoder {
+    pretty: bool,
+}
+
+impl JsonEncoder {
+    /// Create a new JSON encoder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new JSON encoder that produces indented output.
+    pub fn pretty() -> Self {
+        Self { pretty: true }
+    }
+}
+
+impl Encoder for JsonEncoder {
+    fn encode<W: Write>(&self, families: &[MetricFamily], writer: &mut W) -> Result<()> {
+        let families: Vec<_> = families.iter().map(JsonFamily::from).collect();
+
+        let result = if self.pretty {
+            serde_json::to_writer_pretty(writer, &families)
+        } else {
+            serde_json::to_writer(writer, &families)
+        };
+
+        result.map_err(|err| Error::Msg(format!("failed to encode metrics as json: {}", err)))
+    }
+
+    fn format_type(&self) -> &str {
+        JSON_FORMAT
+    }
+}
+
+#[derive(Serialize)]
+struct JsonFamily<'a> {
+    name: &'a str,
+    help: &'a str,
+    #[serde(rename = "type")]
+    ty: &'static str,
+    metrics: Vec<JsonMetric<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonMetric<'a> {
+    labels: BTreeMap<&'a str, &'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp_ms: Option<i64>,
+    #[serde(flatten)]
+    value: JsonValue,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonValue {
+    Value {
+        value: f64,
+    },
+    Histogram {
+        count: u64,
+        sum: f64,
+        buckets: Vec<JsonBucket>,
+    },
+    Summary {
+        count: u64,
+        sum: f64,
+        quantiles: Vec<JsonQuantile>,
+    },
+}
+
+#[derive(Serialize)]
+struct JsonBucket {
+    le: f64,
+    count: u64,
+}
+
+#[derive(Serialize)]
+struct JsonQuantile {
+    quantile: f64,
+    value: f64,
+}
+
+impl<'a> From<&'a MetricFamily> for JsonFamily<'a> {
+    fn from(family: &'a MetricFamily) -> Self {
+        let ty = family.get_field_type();
+
+        JsonFamily {
+            name: family.get_name(),
+            help: family.get_help(),
+            ty: match ty {
+                MetricType::COUNTER => "counter",
+                MetricType::GAUGE => "gauge",
+                MetricType::SUMMARY => "summary",
+                MetricType::UNTYPED => "untyped",
+                MetricType::HISTOGRAM => "histogram",
+            },
+            metrics: family
+                .get_metric()
+                .iter()
+                .map(|metric| JsonMetric::new(metric, ty))
+                .collect(),
+        }
+    }
+}
+
+impl<'a> JsonMetric<'a> {
+    fn new(metric: &'a Metric, ty: MetricType) -> Self {
+        let value = match ty {
+            MetricType::COUNTER => JsonValue::Value {
+                value: metric.get_counter().get_value(),
+            },
+            MetricType::GAUGE => JsonValue::Value {
+                value: metric.get_gauge().get_value(),
+            },
+            MetricType::UNTYPED => JsonValue::Value {
+                value: metric.get_untyped().get_value(),
+            },
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                JsonValue::Histogram {
+                    count: histogram.get_sample_count(),
+                    sum: histogram.get_sample_sum(),
+                    buckets: histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| JsonBucket {
+                            le: bucket.get_upper_bound(),
+                            count: bucket.get_cumulative_count(),
+                        })
+                        .collect(),
+                }
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                JsonValue::Summary {
+                    count: summary.get_sample_count(),
+                    sum: summary.get_sample_sum(),
+                    quantiles: summary
+                        .get_quantile()
+                        .iter()
+                        .map(|quantile| JsonQuantile {
+                            quantile: quantile.get_quantile(),
+                            value: quantile.get_value(),
+                        })
+                        .collect(),
+                }
+            }
+        };
+
+        JsonMetric {
+            labels: metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name(), label.get_value()))
+                .collect(),
+            timestamp_ms: if metric.has_timestamp_ms() {
+                Some(metric.get_timestamp_ms())
+            } else {
+                None
+            },
+            value,
+        }
+    }
+}
@@ -105,12 +105,13 @@
 //!
 //! The derive macro will automatically generate implementation
 //! for the [`MetricStorage`] trait. On top of it, it will generate
-//! three more methods:
+//! four more methods:
 //!
 //! - <code>fn new(registry: &[Registry], ...) -> [`Result`]\<Self\></code>:
 //!
 //!   Creates a new instance of a metric storage and registers all of its metrics
-//!   in the given registry via [`MetricStorage::register`].
+//!   in the given registry via [`MetricStorage::register`]. They can be
+//!   unregistered later via [`MetricStorage::unregister`].
 //!
 //!   This method accepts a reference to a registry, and const labels,
 //!   if storage defines any (see section on [configuring metrics](#configuring-metrics),
@@ -120,6 +121,12 @@
 //!   they are listed in the same order as they appear
 //!   in the `#[metric(labels(...))]` attribute.
 //!
+//! - <code>fn new_multi(registries: &\[(&[str], &[Registry])\], ...) -> [Result]\<Self\></code>:
+//!
+//!   Same as `new`, but registers metrics in several named registries
+//!   via [`MetricStorage::register_multi`]. See the `registries` option
+//!   in the section on [configuring metrics](#configuring-metrics).
+//!
 //! - <code>fn new_unregistered(...) -> [Result]\<Self\></code>:
 //!
 //!   Same as `new`, but doesn't add metrics to any registry. You can use
@@ -191,6 +198,31 @@
 //!   // let google_metrics_2 = Metrics::new(&registry, "https://google.com/").unwrap();
 //!   ```
 //!
+//!   Labels can also be given fixed values, written as `name = value`.
+//!   Such labels are not constructor parameters, and they are not listed
+//!   in [`MetricStorage::const_labels`]. Value can be any expression
+//!   that implements [`ToString`], such as a string literal or an [`env!`]
+//!   macro. It is evaluated every time a storage is created:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("url", region = "eu", version = env!("CARGO_PKG_VERSION")))]
+//!   struct Metrics {
+//!       /// Number of processed requests.
+//!       requests: prometheus::IntCounter,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry, "https://google.com/").unwrap();
+//!
+//!   let families = registry.gather();
+//!   let labels = families[0].get_metric()[0].get_label();
+//!   assert_eq!(labels[0].get_name(), "region");
+//!   assert_eq!(labels[0].get_value(), "eu");
+//!   assert_eq!(labels[1].get_name(), "url");
+//!   ```
+//!
 //!   See the [`const_labels`] field of the [`prometheus::Opts`] struct for more
 //!   info on different label settings.
 //!
@@ -231,6 +263,68 @@
 //!   # }
 //!   ```
 //!
+//! - **const_labels** — const labels with fixed values that are only added
+//!   to this metric, written as `name = value`. Values follow the same rules
+//!   as values of struct-level labels.
+//!
+//!   This allows several fields to share a metric name, as long as they
+//!   have different values of const labels:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Duration of IO operations in seconds.
+//!       #[metric(name = "io_duration_seconds", const_labels(kind = "read"))]
+//!       read_duration_seconds: prometheus::Histogram,
+//!
+//!       /// Duration of IO operations in seconds.
+//!       #[metric(name = "io_duration_seconds", const_labels(kind = "write"))]
+//!       write_duration_seconds: prometheus::Histogram,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   metrics.read_duration_seconds.observe(0.1);
+//!
+//!   let families = registry.gather();
+//!   assert_eq!(families.len(), 1);
+//!   assert_eq!(families[0].get_metric().len(), 2);
+//!   ```
+//!
+//!   Counters can be split the same way, for example, by the outcome
+//!   of an operation:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Number of cache lookups.
+//!       #[metric(name = "cache_ops_total", const_labels(op = "hit"))]
+//!       cache_hits: prometheus::IntCounter,
+//!
+//!       /// Number of cache lookups.
+//!       #[metric(name = "cache_ops_total", const_labels(op = "miss"))]
+//!       cache_misses: prometheus::IntCounter,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   metrics.cache_hits.inc();
+//!
+//!   let families = registry.gather();
+//!   assert_eq!(families.len(), 1);
+//!   assert_eq!(families[0].get_metric().len(), 2);
+//!   ```
+//!
+//!   Fields that share a metric name are registered as a single collector
+//!   with one descriptor, in which their field-level const labels
+//!   become variable labels. Such fields should have the same type,
+//!   help message, variable labels and `registries`, and the same names
+//!   of field-level const labels. This is checked at compile time,
+//!   as well as uniqueness of label values given as string literals.
+//!   They can't use `max_series`.
+//!
 //! - **buckets** — a list of floating point numbers used as histogram
 //!   bucket bounds. Numbers should be listed in ascending order.
 //!
@@ -246,12 +340,226 @@
 //!   # }
 //!   ```
 //!
+//! - **init** — path to a function that initializes the metric instead
+//!   of [`MetricInit::init`]. The function receives [`Opts`], and also
+//!   a vector of buckets if `buckets` are set, and returns a [`Result`]
+//!   whose error converts into [`prometheus::Error`].
+//!
+//!   This allows using collectors from other crates, or constructing
+//!   a metric in a special way, without implementing [`MetricInit`]
+//!   for a newtype.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   fn exponential(
+//!       opts: prometheus::Opts,
+//!       buckets: Vec<f64>,
+//!   ) -> prometheus::Result<prometheus::Histogram> {
+//!       let buckets = prometheus::exponential_buckets(buckets[0], 2.0, 10)?;
+//!       prometheus::Histogram::with_opts(prometheus::HistogramOpts::from(opts).buckets(buckets))
+//!   }
+//!
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Size of request bodies in bytes.
+//!       #[metric(init = "exponential", buckets(64))]
+//!       request_size_bytes: prometheus::Histogram,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry).unwrap();
+//!   metrics.request_size_bytes.observe(100.0);
+//!
+//!   let families = registry.gather();
+//!   let histogram = families[0].get_metric()[0].get_histogram();
+//!   assert_eq!(histogram.get_bucket().len(), 10);
+//!   ```
+//!
+//! - **max_series** — maximum number of label combinations
+//!   a multidimensional metric can hold. Label combinations beyond this limit
+//!   are folded into a single overflow series. The field should be
+//!   wrapped into [`LimitedVec`].
+//!
+//! - **overflow** — label value used for the overflow series,
+//!   `"other"` by default.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # use prometheus_metric_storage::LimitedVec;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   # /// -
+//!   #[metric(labels("token"), max_series = 1000, overflow = "other")]
+//!   trades: LimitedVec<prometheus::IntCounterVec>,
+//!   # }
+//!   ```
+//!
+//! - **ttl_seconds** — number of seconds after which a label combination
+//!   of a multidimensional metric is removed unless it was looked up again.
+//!   The field should be wrapped into [`ExpiringVec`]. This option can't
+//!   be combined with `max_series`.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage_derive::MetricStorage;
+//!   # use prometheus_metric_storage::ExpiringVec;
+//!   # #[derive(MetricStorage)]
+//!   # struct Metrics {
+//!   # /// -
+//!   #[metric(labels("order_uid"), ttl_seconds = 3600)]
+//!   order_fills: ExpiringVec<prometheus::IntCounterVec>,
+//!   # }
+//!   ```
+//!
+//! - **registries** — names of registries this metric should be registered
+//!   in when the storage is created via `new_multi`
+//!   or [`StorageRegistry::new_multi`]. Metrics without this option
+//!   are registered in all registries. A single name can be given
+//!   as `registries = "internal"`. Registering the storage fails
+//!   if none of the listed names matches a given registry.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   #[derive(MetricStorage)]
+//!   struct Metrics {
+//!       /// Number of processed requests.
+//!       requests: prometheus::IntCounter,
+//!
+//!       /// Number of cache misses.
+//!       #[metric(registries("internal", "debug"))]
+//!       cache_misses: prometheus::IntCounter,
+//!   }
+//!
+//!   let public = prometheus::Registry::new();
+//!   let internal = prometheus::Registry::new();
+//!   let metrics = Metrics::new_multi(&[("public", &public), ("internal", &internal)]).unwrap();
+//!
+//!   assert_eq!(public.gather().len(), 1);
+//!   assert_eq!(internal.gather().len(), 2);
+//!
+//!   metrics.unregister_multi(&[("public", &public), ("internal", &internal)]).unwrap();
+//!   assert!(internal.gather().is_empty());
+//!   ```
+//!
+//! Storages can also hold values that are not metrics:
+//!
+//! - **skip** — the field is not a metric, and is initialized
+//!   with [`Default::default`].
+//!
+//! - **default** — a string with an expression that initializes a field
+//!   marked with `skip` instead of [`Default::default`].
+//!
+//! - **const_label** — the field holds a value of a const label declared
+//!   in the struct-level `labels`. The label name is taken from the field name,
+//!   or can be given explicitly, as in `const_label = "endpoint"`.
+//!   The field type should implement `From<String>`.
+//!
+//!   Example:
+//!
+//!   ```
+//!   # use prometheus_metric_storage::MetricStorage;
+//!   # use std::sync::atomic::AtomicUsize;
+//!   #[derive(MetricStorage)]
+//!   #[metric(labels("endpoint"))]
+//!   struct Metrics {
+//!       /// Number of processed requests.
+//!       requests: prometheus::IntCounter,
+//!
+//!       #[metric(const_label)]
+//!       endpoint: String,
+//!
+//!       #[metric(skip)]
+//!       in_flight: AtomicUsize,
+//!
+//!       #[metric(skip, default = "std::time::Duration::from_secs(5)")]
+//!       timeout: std::time::Duration,
+//!   }
+//!
+//!   # let registry = prometheus::Registry::default();
+//!   let metrics = Metrics::new(&registry, "/api").unwrap();
+//!   assert_eq!(metrics.endpoint, "/api");
+//!   assert_eq!(metrics.timeout.as_secs(), 5);
+//!   assert_eq!(registry.gather().len(), 1);
+//!   ```
+//!
+//!   Such fields can't have any other options. They are not included
+//!   in thread-local counterparts of a storage.
+//!
+//! # Local metrics
+//!
+//! Updating shared metrics involves atomic operations, which may cause
+//! contention in tight loops. The [`prometheus`] crate solves this with
+//! [local metrics] that buffer updates and flush them periodically.
+//!
+//! Add `#[metric(local)]` to a storage to generate its thread-local
+//! counterpart. For a storage named `Metrics`, the derive macro will generate
+//! a struct `MetricsLocal` with the same fields, only using local versions
+//! of metrics (see [`MetricLocal`]). It will also generate two methods:
+//!
+//! - `fn local(&self) -> MetricsLocal` for the original storage, which creates
+//!   a new thread-local counterpart;
+//! - `fn flush(&self)` for the local storage, which flushes all buffered
+//!   updates to the original storage. Local storage is also flushed
+//!   when it's dropped.
+//!
+//! ```
+//! # use prometheus_metric_storage::MetricStorage;
+//! #[derive(MetricStorage)]
+//! #[metric(local)]
+//! struct Metrics {
+//!     /// Number of processed items.
+//!     items_processed: prometheus::IntCounter,
+//! }
+//!
+//! # let registry = prometheus::Registry::new();
+//! let metrics = Metrics::new(&registry).unwrap();
+//!
+//! let local = metrics.local();
+//! for _ in 0..1000 {
+//!     local.items_processed.inc();
+//! }
+//! assert_eq!(metrics.items_processed.get(), 0);
+//!
+//! local.flush();
+//! assert_eq!(metrics.items_processed.get(), 1000);
+//! ```
+//!
+//! # Runtime configuration
+//!
+//! Some settings can be overridden at runtime, without rebuilding
+//! the binary. Metric storages consult [`metrics_config`], so operators
+//! can retune histogram buckets, disable specific metrics, or add extra
+//! const labels. Overrides are looked up by the full metric name.
+//! Buckets and const labels are applied when a storage is created,
+//! while disabled metrics are checked when it's registered or unregistered.
+//!
+//! [`MetricsConfig`] can be loaded from environment variables via
+//! [`MetricsConfig::from_env`]. With the `serde` feature enabled,
+//! it can also be loaded from any format supported by serde, such as TOML
+//! or JSON. Either way, it is installed via [`set_metrics_config`].
+//!
+//! Note that overridden buckets only apply to histograms that
+//! are initialized by this crate, and to custom collectors
+//! configured with `#[metric(buckets(...))]`.
+//!
 //! # Supporting custom collectors
 //!
 //! If your project uses custom [collectors], metric storage will not be able
 //! to instantiate them by default. You'll have to implement [`MetricInit`]
 //! and possibly [`HistMetricInit`] for each of the collector you wish to use.
 //!
+//! Metrics scraped from other processes can be re-exposed via
+//! [`FederatedCollector`]. It parses the Prometheus text format
+//! or OpenMetrics, and adds const labels to every parsed metric, so that
+//! a sidecar can expose metrics of its children alongside its own storages.
+//!
 //! # Metric storage registry
 //!
 //! When registering a metric storage, there's a requirement
@@ -337,12 +645,104 @@
 //! # }
 //! ```
 //!
+//! Libraries can be handed a [child registry] instead. Metrics of storages
+//! created through it get a name prefix and extra const labels, so several
+//! instances of the same library don't clash with each other.
+//!
+//! Code that relies on [`default_storage_registry`] can be tested
+//! in isolation by temporarily overriding the default storage registry
+//! with [`with_storage_registry`]. Overriding it with
+//! a [disabled registry](StorageRegistry::disabled) turns metrics off
+//! altogether, while leaving the instrumented code unchanged.
+//!
+//! # Pushing metrics
+//!
+//! Batch jobs may exit before Prometheus gets a chance to scrape them.
+//! With the `push` feature enabled, metrics can be pushed
+//! to a Pushgateway, either once via `StorageRegistry::push_to_gateway`,
+//! or periodically via `Pusher`.
+//!
+//! Workers that can't be scraped at all, for example because they're
+//! behind a NAT, can send metrics to a Prometheus remote-write endpoint
+//! via `RemoteWriter`. It is available with the `remote-write` feature.
+//!
+//! In environments that only run a StatsD agent, metrics can be sent
+//! to it via `StatsdExporter`, which is available with the `statsd` feature.
+//! Labels are sent as DogStatsD tags.
+//!
+//! Cron-style jobs can also publish metrics through node_exporter's
+//! textfile collector. [`StorageRegistry::write_textfile`] atomically
+//! writes metrics to a file, and [`TextfileWriter`] does so periodically.
+//!
+//! # The `metrics` facade
+//!
+//! Many crates report metrics through the [`metrics`](https://docs.rs/metrics) facade.
+//! With the `metrics` feature enabled, `MetricsRecorder` can be installed
+//! as the facade's recorder, so that metrics from such crates end up
+//! in the same [`StorageRegistry`] as metric storages.
+//!
+//! # JSON exposition format
+//!
+//! With the `json` feature enabled, `JsonEncoder` can render contents
+//! of a [`StorageRegistry`] as JSON. It implements [`prometheus::Encoder`],
+//! so it can be used in place of [`prometheus::TextEncoder`]
+//! in debug endpoints.
+//!
+//! # OpenTelemetry
+//!
+//! With the `opentelemetry` feature enabled, contents of a [`StorageRegistry`]
+//! can be exported through an OpenTelemetry metrics pipeline.
+//! Add `OpenTelemetryProducer` to a metric reader, and all metrics
+//! from the registry will be exported alongside OpenTelemetry instruments.
+//!
+//! # The `prometheus-client` backend
+//!
+//! With the `prometheus-client` feature enabled, storages can use metric
+//! types from the [`prometheus-client`](https://docs.rs/prometheus-client)
+//! crate instead of the `prometheus` ones. Add
+//! `#[metric(backend = "prometheus-client")]` to a storage, and its `new`
+//! function will register metrics in a `prometheus_client` registry.
+//! See the `client` module for details.
+//!
+//! # Upgrading from 0.4
+//!
+//! Version 0.5 introduces a dedicated [`Error`] type, which tells
+//! which field of a storage and which metric caused a failure. Generated
+//! functions, methods of [`MetricStorage`], and storage lookups
+//! in [`StorageRegistry`] now return [`Result`] from this crate instead
+//! of [`prometheus::Result`]. This is a breaking change.
+//!
+//! [`Error`] converts into [`prometheus::Error`], so the `?` operator
+//! keeps working in functions that return [`prometheus::Result`].
+//! Code that names the result type explicitly, or matches on variants
+//! of [`prometheus::Error`], has to convert the error:
+//!
+//! ```
+//! # use prometheus_metric_storage::MetricStorage;
+//! # #[derive(MetricStorage)]
+//! # struct Metrics {
+//! #     /// Number of requests that are currently inflight.
+//! #     inflight: prometheus::IntGauge,
+//! # }
+//! let registry = prometheus::Registry::new();
+//!
+//! // Before: `let metrics: prometheus::Result<Metrics> = Metrics::new(&registry);`
+//! let metrics: prometheus::Result<Metrics> = Metrics::new(&registry).map_err(Into::into);
+//! # metrics.unwrap();
+//! ```
+//!
+//! Manual implementations of [`MetricStorage`] have to change
+//! their return types accordingly. [`MetricInit`] and [`HistMetricInit`]
+//! still return [`prometheus::Result`].
+//!
 //! [static metrics]: prometheus#static-metrics
 //! [default registry]: prometheus::default_registry
 //! [collectors]: prometheus::core::Collector
 //! [`subsystem`]: prometheus::Opts#structfield.subsystem
 //! [`const_labels`]: prometheus::Opts#structfield.const_labels
 //! [`MetricVec::with_label_values`]: prometheus::core::MetricVec::with_label_values
+//! [local metrics]: prometheus::local
+//! [child registry]: StorageRegistry::child
 
 #![deny(missing_docs)]
 
@@ -352,20 +752,65 @@ mod test_readme {
     mod test_readme_impl {}
 }
 
+#[cfg(feature = "prometheus-client")]
+pub mod client;
+mod config;
+mod error;
+mod expiring;
+mod federation;
+mod group;
+#[cfg(feature = "json")]
+mod json;
+mod limited;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+#[cfg(feature = "push")]
+mod push;
+#[cfg(feature = "metrics")]
+mod recorder;
+#[cfg(feature = "remote-write")]
+mod remote_write;
+#[cfg(feature = "statsd")]
+mod statsd;
+mod textfile;
+mod worker;
+
+pub use config::{metrics_config, set_metrics_config, MetricConfig, MetricsConfig};
+pub use error::{Error, Result};
+pub use expiring::ExpiringVec;
+pub use federation::{parse_text, FederatedCollector};
+#[cfg(feature = "json")]
+pub use json::{JsonEncoder, JSON_FORMAT};
+pub use limited::LimitedVec;
+#[cfg(feature = "opentelemetry")]
+pub use otel::OpenTelemetryProducer;
+#[cfg(feature = "push")]
+pub use push::{Pusher, PusherBuilder};
+#[cfg(feature = "metrics")]
+pub use recorder::{Matcher, MetricsRecorder, MetricsRecorderBuilder};
+#[cfg(feature = "remote-write")]
+pub use remote_write::{RemoteWriter, RemoteWriterBuilder};
+#[cfg(feature = "statsd")]
+pub use statsd::{StatsdExporter, StatsdExporterBuilder};
+pub use textfile::{TextfileWriter, TextfileWriterBuilder};
+
 use prometheus::core::Collector;
 use prometheus::proto::MetricFamily;
 use std::any::{Any, TypeId};
+use std::cell::Cell;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
 use std::pin::Pin;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 #[doc(hidden)]
-pub use prometheus::{Error, Opts, Registry, Result};
+pub use group::{MetricGroup, MetricGroupBuilder};
+#[doc(hidden)]
+pub use prometheus::{Opts, Registry};
 
-/// Generates implementation for [`MetricStorage`] and three additional
-/// methods: `new`, `new_unregistered`, `instance`.
+/// Generates implementation for [`MetricStorage`] and four additional
+/// methods: `new`, `new_multi`, `new_unregistered`, `instance`.
 ///
 /// See the [crate-level] documentation for more info.
 ///
@@ -376,8 +821,15 @@ pub use prometheus_metric_storage_derive::MetricStorage;
 ///
 /// Storage ID consists of a type ID and static label values
 /// concatenated into a single string with zero bytes as a delimiter.
+/// For [child registries], the string is prefixed with the registry's
+/// namespace and extra const labels.
+///
+/// [child registries]: StorageRegistry::child
 type StorageId = (TypeId, String);
 
+/// Storages saved in [`StorageRegistry`], by their IDs.
+type Storages = HashMap<StorageId, Pin<Box<dyn Any + Send + Sync>>>;
+
 /// Wrapper for prometheus' [`Registry`] that keeps track of registered
 /// storages, and helps to avoid "already registered" errors without
 /// having to use lazy statics.
@@ -398,13 +850,31 @@ pub struct StorageRegistry {
     /// The underlying metrics registry.
     registry: Registry,
 
-    /// Saved registered storages.
+    /// All registries with their names, if this storage registry was created
+    /// via [`StorageRegistry::new_multi`]. The first one is `registry`.
+    registries: Vec<(String, Registry)>,
+
+    /// Saved registered storages, shared with all child registries.
     ///
     /// # Safety
     ///
     /// Storages in this hashmap must not be removed or replaced.
-    /// They must only be dropped when this registry is dropped.
-    storages: Mutex<HashMap<StorageId, Pin<Box<dyn Any + Send + Sync>>>>,
+    /// They must only be dropped when the last registry
+    /// that shares them is dropped.
+    storages: Arc<Mutex<Storages>>,
+
+    /// If set, storages are created but never registered.
+    disabled: bool,
+
+    /// Namespace for metrics of storages created through this registry.
+    namespace: String,
+
+    /// Const labels added to metrics of storages created
+    /// through this registry.
+    const_labels: HashMap<String, String>,
+
+    /// Namespace and extra const labels encoded for use in storage IDs.
+    scope: String,
 }
 
 impl StorageRegistry {
@@ -412,10 +882,190 @@ impl StorageRegistry {
     pub fn new(registry: prometheus::Registry) -> Self {
         Self {
             registry,
+            registries: Vec::new(),
             storages: Default::default(),
+            disabled: false,
+            namespace: String::new(),
+            const_labels: HashMap::new(),
+            scope: String::new(),
+        }
+    }
+
+    /// Create a storage registry that registers storages in several
+    /// named registries.
+    ///
+    /// Storages are registered via [`MetricStorage::register_multi`],
+    /// so their fields can be routed to some of the registries
+    /// with `#[metric(registries(...))]`. This is useful for exposing
+    /// some metrics on both a public and an internal endpoint.
+    ///
+    /// The first registry is used by functions that work with a single
+    /// registry, such as [`registry`], [`register`] and [`gather`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `registries` is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{MetricStorage, StorageRegistry};
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of processed requests.
+    ///     requests: prometheus::IntCounter,
+    ///
+    ///     /// Number of cache misses.
+    ///     #[metric(registries = "internal")]
+    ///     cache_misses: prometheus::IntCounter,
+    /// }
+    ///
+    /// let public = prometheus::Registry::new();
+    /// let internal = prometheus::Registry::new();
+    /// let registry = StorageRegistry::new_multi(&[("public", &public), ("internal", &internal)]);
+    ///
+    /// Metrics::instance(&registry).unwrap();
+    ///
+    /// assert_eq!(public.gather().len(), 1);
+    /// assert_eq!(internal.gather().len(), 2);
+    /// ```
+    ///
+    /// [`registry`]: StorageRegistry::registry
+    /// [`register`]: StorageRegistry::register
+    /// [`gather`]: StorageRegistry::gather
+    pub fn new_multi(registries: &[(&str, &Registry)]) -> Self {
+        assert!(
+            !registries.is_empty(),
+            "StorageRegistry::new_multi requires at least one registry"
+        );
+
+        let registries: Vec<_> = registries
+            .iter()
+            .map(|&(name, registry)| (name.to_string(), registry.clone()))
+            .collect();
+
+        Self {
+            registry: registries[0].1.clone(),
+            registries,
+            ..Self::default()
+        }
+    }
+
+    /// Create a storage registry that doesn't register anything.
+    ///
+    /// Storages returned by this registry are created and shared
+    /// as usual, but their metrics are never registered, so nothing
+    /// is ever reported. This allows running instrumented code unchanged
+    /// in environments that don't need metrics, such as benchmarks.
+    ///
+    /// Combine it with [`with_storage_registry`] to disable code
+    /// that uses [`default_storage_registry`]:
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{
+    /// #     default_storage_registry, with_storage_registry, MetricStorage, StorageRegistry,
+    /// # };
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of processed requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = Box::leak(Box::new(StorageRegistry::disabled()));
+    ///
+    /// with_storage_registry(registry, || {
+    ///     let metrics = Metrics::instance(default_storage_registry()).unwrap();
+    ///     metrics.requests.inc();
+    /// });
+    ///
+    /// assert!(registry.gather().is_empty());
+    /// assert!(prometheus::gather().is_empty());
+    /// ```
+    pub fn disabled() -> Self {
+        Self {
+            disabled: true,
+            ..Self::default()
+        }
+    }
+
+    /// Create a scoped view of this registry.
+    ///
+    /// Storages created through the returned registry are registered
+    /// in the same underlying [`Registry`], but names of their metrics
+    /// are prefixed with `prefix`, and `extra_const_labels` are added
+    /// to their const labels. This way, a library can be handed
+    /// a child registry, and its metrics will be namespaced per instance
+    /// without the library having to declare const labels in its storages.
+    ///
+    /// Prefixes of nested child registries are joined with underscores,
+    /// and their extra const labels are merged. Creating a storage fails
+    /// if one of its own const labels is also set by the registry.
+    ///
+    /// Storages are shared between the parent registry and all of its
+    /// children, so dropping a child doesn't unregister anything.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{MetricStorage, StorageRegistry};
+    /// # use std::collections::HashMap;
+    /// #[derive(MetricStorage)]
+    /// #[metric(subsystem = "pool")]
+    /// struct Metrics {
+    ///     /// Number of open connections.
+    ///     connections: prometheus::IntGauge,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    ///
+    /// let mut labels = HashMap::new();
+    /// labels.insert("db".to_string(), "users".to_string());
+    /// let child = registry.child("postgres", labels);
+    ///
+    /// Metrics::instance(&child).unwrap().connections.set(3);
+    ///
+    /// let families = registry.gather();
+    /// assert_eq!(families[0].get_name(), "postgres_pool_connections");
+    /// assert_eq!(families[0].get_metric()[0].get_label()[0].get_value(), "users");
+    /// ```
+    pub fn child(&self, prefix: &str, extra_const_labels: HashMap<String, String>) -> Self {
+        let namespace = match (self.namespace.as_str(), prefix) {
+            (namespace, "") => namespace.to_string(),
+            ("", prefix) => prefix.to_string(),
+            (namespace, prefix) => format!("{}_{}", namespace, prefix),
+        };
+
+        let mut const_labels = self.const_labels.clone();
+        const_labels.extend(extra_const_labels);
+
+        let mut sorted_labels: Vec<_> = const_labels.iter().collect();
+        sorted_labels.sort_unstable();
+
+        let mut scope = namespace.clone();
+        scope.push('\0');
+        for (name, value) in sorted_labels {
+            scope.push_str(name);
+            scope.push('\0');
+            scope.push_str(value);
+            scope.push('\0');
+        }
+
+        Self {
+            registry: self.registry.clone(),
+            registries: self.registries.clone(),
+            storages: self.storages.clone(),
+            disabled: self.disabled,
+            namespace,
+            const_labels,
+            scope,
         }
     }
 
+    /// Check if this registry was created via [`StorageRegistry::disabled`].
+    pub fn is_disabled(&self) -> bool {
+        self.disabled
+    }
+
     /// Return a reference to the underlying [`Registry`].
     pub fn registry(&self) -> &Registry {
         &self.registry
@@ -433,8 +1083,16 @@ impl StorageRegistry {
     /// Return an error if the given metric was already registered
     /// when this function is called.
     ///
+    /// Does nothing if this registry is [disabled].
+    ///
     /// See [`Registry::unregister`] for more info.
-    pub fn register(&self, c: Box<dyn Collector>) -> Result<()> {
+    ///
+    /// [disabled]: StorageRegistry::disabled
+    pub fn register(&self, c: Box<dyn Collector>) -> prometheus::Result<()> {
+        if self.disabled {
+            return Ok(());
+        }
+
         self.registry.register(c)
     }
 
@@ -443,8 +1101,16 @@ impl StorageRegistry {
     /// Returns an error if the given metric was not registered
     /// when this function is called.
     ///
+    /// Does nothing if this registry is [disabled].
+    ///
     /// See [`Registry::unregister`] for more info.
-    pub fn unregister(&self, c: Box<dyn Collector>) -> Result<()> {
+    ///
+    /// [disabled]: StorageRegistry::disabled
+    pub fn unregister(&self, c: Box<dyn Collector>) -> prometheus::Result<()> {
+        if self.disabled {
+            return Ok(());
+        }
+
         self.registry.unregister(c)
     }
 
@@ -463,17 +1129,16 @@ impl StorageRegistry {
         &self,
         const_labels: HashMap<String, String>,
     ) -> Result<&T> {
-        let metric_id = Self::make_id::<T>(&const_labels)?;
+        let metric_id = self.make_id::<T>(&const_labels)?;
 
         let mut storages = self.storages.lock().unwrap();
 
         let storage = match storages.entry(metric_id) {
             Entry::Occupied(entry) => entry.into_mut().downcast_ref::<T>().unwrap(),
             Entry::Vacant(_) => {
-                return Err(Error::Msg(format!(
-                    "metric storage {} not found",
-                    std::any::type_name::<T>()
-                )))
+                return Err(Error::StorageNotFound {
+                    storage: std::any::type_name::<T>().to_string(),
+                })
             }
         };
 
@@ -487,20 +1152,25 @@ impl StorageRegistry {
     /// storage does not exist in this registry, create it and register
     /// its metrics.
     ///
+    /// If this registry is [disabled], the created storage
+    /// will not be registered.
+    ///
     /// Returns an error if the given labels are invalid or if storage creation
     /// has failed.
+    ///
+    /// [disabled]: StorageRegistry::disabled
     pub fn get_or_create_storage<T: MetricStorage + Send + Sync + 'static>(
         &self,
         const_labels: HashMap<String, String>,
     ) -> Result<&T> {
-        let metric_id = Self::make_id::<T>(&const_labels)?;
+        let metric_id = self.make_id::<T>(&const_labels)?;
 
         let mut storages = self.storages.lock().unwrap();
 
         let storage = match storages.entry(metric_id) {
             Entry::Occupied(entry) => entry.into_mut().downcast_ref::<T>().unwrap(),
             Entry::Vacant(entry) => {
-                let storage = T::from_const_labels(&self.registry, const_labels)?;
+                let storage = self.create_storage::<T>(const_labels)?;
                 entry.insert(Box::pin(storage)).downcast_ref::<T>().unwrap()
             }
         };
@@ -508,7 +1178,7 @@ impl StorageRegistry {
         // Safety:
         //
         // We never remove storages from this registry, thus they will live
-        // for as long as this registry lives. We've also made storages
+        // for as long as this registry and its children live. We've also made storages
         // `Pin`, so we never move them. This means that a reference
         // to a storage will stay valid for as long as this registry lives.
         //
@@ -524,31 +1194,62 @@ impl StorageRegistry {
     }
 
     fn make_id<T: MetricStorage + Send + Sync + 'static>(
+        &self,
         const_labels: &HashMap<String, String>,
     ) -> Result<StorageId> {
-        let labels_spec = T::const_labels();
-
-        if labels_spec.len() != const_labels.len() {
-            return Err(Error::Msg(format!(
-                "invalid number of const labels: expected {}, got {}",
-                labels_spec.len(),
-                const_labels.len()
-            )));
-        }
+        check_const_labels(T::const_labels(), const_labels)?;
 
-        let mut values = String::new();
+        let mut values = self.scope.clone();
 
         for &label in T::const_labels() {
-            if let Some(value) = const_labels.get(label) {
-                values.push_str(value);
-                values.push('\0');
-            } else {
-                return Err(Error::Msg(format!("label {:?} is missing", label)));
-            }
+            values.push_str(&const_labels[label]);
+            values.push('\0');
         }
 
         Ok((TypeId::of::<T>(), values))
     }
+
+    /// Create a new storage and register it unless this registry is disabled.
+    fn create_storage<T: MetricStorage>(&self, const_labels: HashMap<String, String>) -> Result<T> {
+        let storage = if self.scope.is_empty() {
+            T::from_const_labels_unregistered(const_labels)?
+        } else {
+            let const_labels = self.scoped_const_labels(const_labels)?;
+            T::from_namespace_unregistered(&self.namespace, const_labels)?
+        };
+
+        if self.disabled {
+            // Nothing to register.
+        } else if self.registries.is_empty() {
+            storage.register(&self.registry)?;
+        } else {
+            let registries: Vec<_> = self
+                .registries
+                .iter()
+                .map(|(name, registry)| (name.as_str(), registry))
+                .collect();
+            storage.register_multi(&registries)?;
+        }
+
+        Ok(storage)
+    }
+
+    /// Add extra const labels of this registry to the given labels.
+    fn scoped_const_labels(
+        &self,
+        mut const_labels: HashMap<String, String>,
+    ) -> Result<HashMap<String, String>> {
+        for (name, value) in &self.const_labels {
+            if const_labels.contains_key(name) {
+                return Err(Error::DuplicateConstLabel {
+                    label: name.clone(),
+                });
+            }
+            const_labels.insert(name.clone(), value.clone());
+        }
+
+        Ok(const_labels)
+    }
 }
 
 impl Default for StorageRegistry {
@@ -563,14 +1264,83 @@ impl Debug for StorageRegistry {
     }
 }
 
+thread_local! {
+    /// Storage registry that overrides the default one for the current thread.
+    static CURRENT_STORAGE_REGISTRY: Cell<Option<&'static StorageRegistry>> = const { Cell::new(None) };
+}
+
 /// Get the default storage registry that uses [`prometheus::default_registry`].
+///
+/// If the current thread is executing within [`with_storage_registry`],
+/// returns the overriding registry instead.
 pub fn default_storage_registry() -> &'static StorageRegistry {
     lazy_static::lazy_static! {
         static ref REGISTRY: StorageRegistry =
             StorageRegistry::new(prometheus::default_registry().clone());
     }
 
-    &REGISTRY as &StorageRegistry
+    CURRENT_STORAGE_REGISTRY
+        .with(Cell::get)
+        .unwrap_or(&REGISTRY as &StorageRegistry)
+}
+
+/// Run the given function with `registry` overriding the default
+/// storage registry on the current thread.
+///
+/// While `f` is running, [`default_storage_registry`] will return `registry`
+/// instead of the global storage registry. This allows tests that run
+/// in parallel to observe metrics of the code they're testing without
+/// seeing each other's counts.
+///
+/// The override is only visible to the current thread. Threads spawned
+/// from `f`, as well as async tasks that are moved between threads
+/// by an executor, will see the global storage registry. Overrides can be
+/// nested; the previous override is restored when `f` returns or panics.
+///
+/// Since storages returned by [`default_storage_registry`] have a `'static`
+/// lifetime, the overriding registry should be `'static` as well.
+/// In tests, it can be obtained via [`Box::leak`].
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{
+/// #     default_storage_registry, with_storage_registry, MetricStorage, StorageRegistry,
+/// # };
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of processed requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// fn process_request() {
+///     Metrics::instance(default_storage_registry())
+///         .unwrap()
+///         .requests
+///         .inc();
+/// }
+///
+/// let registry = Box::leak(Box::new(StorageRegistry::default()));
+///
+/// with_storage_registry(registry, || {
+///     process_request();
+///     process_request();
+/// });
+///
+/// assert_eq!(Metrics::instance(registry).unwrap().requests.get(), 2);
+/// ```
+pub fn with_storage_registry<R>(registry: &'static StorageRegistry, f: impl FnOnce() -> R) -> R {
+    struct Guard(Option<&'static StorageRegistry>);
+
+    impl Drop for Guard {
+        fn drop(&mut self) {
+            CURRENT_STORAGE_REGISTRY.with(|current| current.set(self.0));
+        }
+    }
+
+    let _guard = Guard(CURRENT_STORAGE_REGISTRY.with(|current| current.replace(Some(registry))));
+
+    f()
 }
 
 /// Common interface for metric storages.
@@ -615,8 +1385,153 @@ pub trait MetricStorage: Sized {
     /// that's being created, this function will return an error.
     fn from_const_labels_unregistered(const_labels: HashMap<String, String>) -> Result<Self>;
 
+    /// Create a new instance of this storage with the given namespace
+    /// prepended to names of all of its metrics.
+    ///
+    /// This function is used by [child registries]. Unlike
+    /// [`from_const_labels_unregistered`], it doesn't check const labels:
+    /// labels that are not declared in the storage are added to every metric.
+    ///
+    /// The default implementation only supports an empty namespace.
+    /// It is overridden by the derive macro.
+    ///
+    /// [child registries]: StorageRegistry::child
+    /// [`from_const_labels_unregistered`]: MetricStorage::from_const_labels_unregistered
+    fn from_namespace_unregistered(
+        namespace: &str,
+        const_labels: HashMap<String, String>,
+    ) -> Result<Self> {
+        if namespace.is_empty() {
+            Self::from_const_labels_unregistered(const_labels)
+        } else {
+            Err(Error::Unsupported {
+                storage: std::any::type_name::<Self>().to_string(),
+                operation: "namespaces".to_string(),
+            })
+        }
+    }
+
     /// Register all metrics from this storage in the given registry.
+    ///
+    /// Registration is transactional: if one of the metrics can't be
+    /// registered, metrics that were registered before it are unregistered,
+    /// and the returned error names the offending field and metric.
+    /// Thus, a failed registration can be safely retried.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of processed requests.
+    ///     requests: prometheus::IntCounter,
+    ///
+    ///     /// Number of failed requests.
+    ///     errors: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::new();
+    ///
+    /// // Some other component has already registered a metric
+    /// // with the same name.
+    /// let errors = prometheus::IntCounter::new("errors", "Number of failed requests.").unwrap();
+    /// registry.register(Box::new(errors.clone())).unwrap();
+    ///
+    /// let err = Metrics::new(&registry).err().unwrap();
+    /// assert!(err.to_string().contains("field `errors`"));
+    ///
+    /// // The `requests` metric was rolled back.
+    /// registry.unregister(Box::new(errors)).unwrap();
+    /// assert!(registry.gather().is_empty());
+    /// Metrics::new(&registry).unwrap();
+    /// ```
     fn register(&self, registry: &Registry) -> Result<()>;
+
+    /// Unregister all metrics of this storage from the given registry.
+    ///
+    /// This is the inverse of [`register`]. It allows detaching a storage
+    /// when a component shuts down or is reconfigured. All metrics
+    /// are unregistered even if some of them fail; the returned error
+    /// lists fields that failed.
+    ///
+    /// The default implementation always returns an error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::MetricStorage;
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of processed requests.
+    ///     requests: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = prometheus::Registry::new();
+    /// let metrics = Metrics::new(&registry).unwrap();
+    ///
+    /// metrics.unregister(&registry).unwrap();
+    /// assert!(registry.gather().is_empty());
+    ///
+    /// let err = metrics.unregister(&registry).unwrap_err();
+    /// assert!(err.to_string().contains("`requests`"));
+    /// ```
+    ///
+    /// [`register`]: MetricStorage::register
+    fn unregister(&self, registry: &Registry) -> Result<()> {
+        let _ = registry;
+        Err(Error::Unsupported {
+            storage: std::any::type_name::<Self>().to_string(),
+            operation: "unregistering".to_string(),
+        })
+    }
+
+    /// Register metrics from this storage in several named registries.
+    ///
+    /// Fields marked with `#[metric(registries(...))]` are only registered
+    /// in registries with the listed names; other fields are registered
+    /// in all of them.
+    ///
+    /// If registration in one of the registries fails, metrics that were
+    /// already registered are unregistered, and an error is returned.
+    /// A storage with a field whose `registries` names match none
+    /// of the given registries can't be registered.
+    ///
+    /// The default implementation registers all metrics in all registries.
+    /// It can only roll back a failed registration if the storage
+    /// implements [`unregister`].
+    ///
+    /// [`unregister`]: MetricStorage::unregister
+    fn register_multi(&self, registries: &[(&str, &Registry)]) -> Result<()> {
+        for (i, &(_, registry)) in registries.iter().enumerate() {
+            if let Err(err) = self.register(registry) {
+                for &(_, registry) in &registries[..i] {
+                    let _ = self.unregister(registry);
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unregister metrics from this storage from several named registries.
+    ///
+    /// This is the inverse of [`register_multi`]; it should be given
+    /// the same registries. All metrics are unregistered even
+    /// if some of them fail; the returned error lists fields that failed.
+    ///
+    /// The default implementation calls [`unregister`] for each registry,
+    /// and returns the first error.
+    ///
+    /// [`register_multi`]: MetricStorage::register_multi
+    /// [`unregister`]: MetricStorage::unregister
+    fn unregister_multi(&self, registries: &[(&str, &Registry)]) -> Result<()> {
+        let mut result = Ok(());
+        for &(_, registry) in registries {
+            result = result.and(self.unregister(registry));
+        }
+        result
+    }
 }
 
 /// This trait is used to initialize metrics.
@@ -629,7 +1544,7 @@ pub trait MetricStorage: Sized {
 /// [`init`]: MetricInit::init
 pub trait MetricInit: Sized {
     /// Initialize a new instance of the metric using the given options.
-    fn init(opts: prometheus::Opts) -> Result<Self>;
+    fn init(opts: prometheus::Opts) -> prometheus::Result<Self>;
 }
 
 /// This trait is used to initialize metrics that accept buckets.
@@ -642,31 +1557,229 @@ pub trait MetricInit: Sized {
 /// options appear in the metric config.
 pub trait HistMetricInit: Sized {
     /// Initialize a new instance of the metric using the given options.
-    fn init(opts: prometheus::Opts, buckets: Vec<f64>) -> Result<Self>;
+    fn init(opts: prometheus::Opts, buckets: Vec<f64>) -> prometheus::Result<Self>;
+}
+
+/// This trait is used to create thread-local counterparts of metrics.
+///
+/// Storages marked with `#[metric(local)]` use this trait to create
+/// and flush their [local counterparts](crate#local-metrics).
+///
+/// Counters and histograms are replaced with their unsynchronized
+/// local versions from [`prometheus::local`]. Gauges don't have local
+/// versions, so they're shared with the original storage. [`LimitedVec`]
+/// and [`ExpiringVec`] are shared as well, because local versions
+/// of their underlying collectors would bypass the series limit
+/// and the tracking of stale series.
+pub trait MetricLocal {
+    /// Thread-local counterpart of this metric.
+    type Local;
+
+    /// Create a new thread-local counterpart of this metric.
+    fn local(&self) -> Self::Local;
+
+    /// Flush updates buffered in the local counterpart to this metric.
+    fn flush(local: &Self::Local);
+}
+
+/// Registers collectors of a storage one by one. If registration is not
+/// committed, already registered collectors are unregistered when this
+/// struct is dropped, so that a failed registration leaves no traces.
+#[doc(hidden)]
+#[derive(Default)]
+pub struct Registration<'a> {
+    registered: Vec<(&'a Registry, Box<dyn Collector>)>,
+}
+
+impl<'a> Registration<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a collector that belongs to the given field.
+    pub fn register<C: Collector + Clone + 'static>(
+        &mut self,
+        registry: &'a Registry,
+        field: &str,
+        collector: &C,
+    ) -> Result<()> {
+        match registry.register(Box::new(collector.clone())) {
+            Ok(()) => {
+                self.registered
+                    .push((registry, Box::new(collector.clone())));
+                Ok(())
+            }
+            Err(source) => Err(Error::Registration {
+                field: field.to_string(),
+                metric: metric_name(collector),
+                source,
+            }),
+        }
+    }
+
+    /// Register a group of collectors that belong to the given fields
+    /// and share a metric name. Does nothing if the group is empty.
+    pub fn register_group(
+        &mut self,
+        registry: &'a Registry,
+        fields: &str,
+        group: MetricGroupBuilder,
+    ) -> Result<()> {
+        match build_group(fields, group)? {
+            Some(group) => self.register(registry, fields, &group),
+            None => Ok(()),
+        }
+    }
+
+    /// Keep all registered collectors.
+    pub fn commit(mut self) {
+        self.registered.clear();
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        for (registry, collector) in self.registered.drain(..).rev() {
+            let _ = registry.unregister(collector);
+        }
+    }
+}
+
+/// Initialize a metric of the given field, adding the field
+/// and metric names to the error.
+#[doc(hidden)]
+pub fn init_field<T>(
+    field: &str,
+    opts: &Opts,
+    init: impl FnOnce() -> prometheus::Result<T>,
+) -> Result<T> {
+    init().map_err(|source| Error::FieldInit {
+        field: field.to_string(),
+        metric: opts.fq_name(),
+        source,
+    })
+}
+
+/// Unregister a collector that belongs to the given field.
+#[doc(hidden)]
+pub fn unregister_field<C: Collector + Clone + 'static>(
+    registry: &Registry,
+    field: &str,
+    collector: &C,
+) -> Result<()> {
+    registry
+        .unregister(Box::new(collector.clone()))
+        .map_err(|source| Error::Unregistration {
+            field: field.to_string(),
+            metric: metric_name(collector),
+            source,
+        })
+}
+
+/// Unregister a group of collectors that belong to the given fields.
+#[doc(hidden)]
+pub fn unregister_group(
+    registry: &Registry,
+    fields: &str,
+    group: MetricGroupBuilder,
+) -> Result<()> {
+    match build_group(fields, group)? {
+        Some(group) => unregister_field(registry, fields, &group),
+        None => Ok(()),
+    }
+}
+
+fn build_group(fields: &str, group: MetricGroupBuilder) -> Result<Option<MetricGroup>> {
+    let metric = group.metric_name();
+    group.build().map_err(|source| Error::Registration {
+        field: fields.to_string(),
+        metric,
+        source,
+    })
+}
+
+/// Check that the given const labels match the declared ones.
+#[doc(hidden)]
+pub fn check_const_labels(expected: &[&str], const_labels: &HashMap<String, String>) -> Result<()> {
+    if const_labels.len() != expected.len() {
+        return Err(Error::WrongConstLabelCount {
+            expected: expected.len(),
+            got: const_labels.len(),
+        });
+    }
+
+    for &label in expected {
+        if !const_labels.contains_key(label) {
+            return Err(Error::MissingConstLabel {
+                label: label.to_string(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a field with the `registries` option is registered
+/// in at least one of the given registries.
+#[doc(hidden)]
+pub fn check_registry_names(
+    registries: &[(&str, &Registry)],
+    field: &str,
+    names: &[&str],
+) -> Result<()> {
+    if registries.iter().any(|(name, _)| names.contains(name)) {
+        Ok(())
+    } else {
+        Err(Error::UnknownRegistry {
+            field: field.to_string(),
+            registries: names.iter().map(|name| name.to_string()).collect(),
+        })
+    }
+}
+
+/// Combine errors of several fields into one.
+#[doc(hidden)]
+pub fn collect_failures(mut failed: Vec<Error>) -> Result<()> {
+    match failed.len() {
+        0 => Ok(()),
+        1 => Err(failed.remove(0)),
+        _ => Err(Error::Multiple(failed)),
+    }
+}
+
+/// Full name of the first metric reported by a collector.
+fn metric_name(collector: &dyn Collector) -> String {
+    collector
+        .desc()
+        .first()
+        .map_or_else(String::new, |desc| desc.fq_name.clone())
 }
 
 // Impls
 
 impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericGauge<T> {
-    fn init(opts: Opts) -> Result<Self> {
+    fn init(opts: Opts) -> prometheus::Result<Self> {
         Self::with_opts(opts)
     }
 }
 
 impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericCounter<T> {
-    fn init(opts: Opts) -> Result<Self> {
+    fn init(opts: Opts) -> prometheus::Result<Self> {
         Self::with_opts(opts)
     }
 }
 
 impl MetricInit for prometheus::Histogram {
-    fn init(opts: Opts) -> Result<Self> {
-        Self::with_opts(opts.into())
+    fn init(opts: Opts) -> prometheus::Result<Self> {
+        match metrics_config().buckets(&opts.fq_name()) {
+            Some(buckets) => HistMetricInit::init(opts, buckets.to_vec()),
+            None => Self::with_opts(opts.into()),
+        }
     }
 }
 
 impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericGaugeVec<T> {
-    fn init(mut opts: Opts) -> Result<Self> {
+    fn init(mut opts: Opts) -> prometheus::Result<Self> {
         let labels = std::mem::take(&mut opts.variable_labels);
         let labels_view: Vec<_> = labels.iter().map(AsRef::as_ref).collect();
         Self::new(opts, &labels_view)
@@ -674,7 +1787,7 @@ impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericGaugeV
 }
 
 impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericCounterVec<T> {
-    fn init(mut opts: Opts) -> Result<Self> {
+    fn init(mut opts: Opts) -> prometheus::Result<Self> {
         let labels = std::mem::take(&mut opts.variable_labels);
         let labels_view: Vec<_> = labels.iter().map(AsRef::as_ref).collect();
         Self::new(opts, &labels_view)
@@ -682,7 +1795,11 @@ impl<T: prometheus::core::Atomic> MetricInit for prometheus::core::GenericCounte
 }
 
 impl MetricInit for prometheus::HistogramVec {
-    fn init(mut opts: Opts) -> Result<Self> {
+    fn init(mut opts: Opts) -> prometheus::Result<Self> {
+        if let Some(buckets) = metrics_config().buckets(&opts.fq_name()) {
+            return HistMetricInit::init(opts, buckets.to_vec());
+        }
+
         let labels = std::mem::take(&mut opts.variable_labels);
         let labels_view: Vec<_> = labels.iter().map(AsRef::as_ref).collect();
         Self::new(opts.into(), &labels_view)
@@ -690,17 +1807,105 @@ impl MetricInit for prometheus::HistogramVec {
 }
 
 impl HistMetricInit for prometheus::Histogram {
-    fn init(opts: Opts, buckets: Vec<f64>) -> Result<Self> {
+    fn init(opts: Opts, buckets: Vec<f64>) -> prometheus::Result<Self> {
         let opts: prometheus::HistogramOpts = opts.into();
         Self::with_opts(opts.buckets(buckets))
     }
 }
 
 impl HistMetricInit for prometheus::HistogramVec {
-    fn init(mut opts: Opts, buckets: Vec<f64>) -> Result<Self> {
+    fn init(mut opts: Opts, buckets: Vec<f64>) -> prometheus::Result<Self> {
         let labels = std::mem::take(&mut opts.variable_labels);
         let labels_view: Vec<_> = labels.iter().map(AsRef::as_ref).collect();
         let opts: prometheus::HistogramOpts = opts.into();
         Self::new(opts.buckets(buckets), &labels_view)
     }
 }
+
+impl<T: prometheus::core::Atomic> MetricLocal for prometheus::core::GenericCounter<T> {
+    type Local = prometheus::core::GenericLocalCounter<T>;
+
+    fn local(&self) -> Self::Local {
+        prometheus::core::GenericCounter::local(self)
+    }
+
+    fn flush(local: &Self::Local) {
+        local.flush()
+    }
+}
+
+impl<T: prometheus::core::Atomic> MetricLocal for prometheus::core::GenericCounterVec<T> {
+    type Local = prometheus::core::GenericLocalCounterVec<T>;
+
+    fn local(&self) -> Self::Local {
+        prometheus::core::GenericCounterVec::local(self)
+    }
+
+    fn flush(local: &Self::Local) {
+        local.flush()
+    }
+}
+
+impl MetricLocal for prometheus::Histogram {
+    type Local = prometheus::local::LocalHistogram;
+
+    fn local(&self) -> Self::Local {
+        prometheus::Histogram::local(self)
+    }
+
+    fn flush(local: &Self::Local) {
+        local.flush()
+    }
+}
+
+impl MetricLocal for prometheus::HistogramVec {
+    type Local = prometheus::local::LocalHistogramVec;
+
+    fn local(&self) -> Self::Local {
+        prometheus::HistogramVec::local(self)
+    }
+
+    fn flush(local: &Self::Local) {
+        local.flush()
+    }
+}
+
+impl<T: prometheus::core::Atomic> MetricLocal for prometheus::core::GenericGauge<T> {
+    type Local = Self;
+
+    fn local(&self) -> Self::Local {
+        self.clone()
+    }
+
+    fn flush(_local: &Self::Local) {}
+}
+
+impl<T: prometheus::core::Atomic> MetricLocal for prometheus::core::GenericGaugeVec<T> {
+    type Local = Self;
+
+    fn local(&self) -> Self::Local {
+        self.clone()
+    }
+
+    fn flush(_local: &Self::Local) {}
+}
+
+impl<V: Clone> MetricLocal for LimitedVec<V> {
+    type Local = Self;
+
+    fn local(&self) -> Self::Local {
+        self.clone()
+    }
+
+    fn flush(_local: &Self::Local) {}
+}
+
+impl<V: Clone> MetricLocal for ExpiringVec<V> {
+    type Local = Self;
+
+    fn local(&self) -> Self::Local {
+        self.clone()
+    }
+
+    fn flush(_local: &Self::Local) {}
+}
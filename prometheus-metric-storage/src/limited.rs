@@ -0,0 +1,193 @@
+//! Cardinality limits for multidimensional metrics.
+
+use crate::Opts;
+use prometheus::core::{Collector, Desc, MetricVec, MetricVecBuilder};
+use prometheus::proto::MetricFamily;
+use prometheus::{IntCounter, Result};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// A wrapper for [`MetricVec`] that limits the number of label
+/// combinations it can hold.
+///
+/// Once the limit is reached, new label combinations are folded into
+/// a single overflow series, all labels of which are set to the overflow
+/// value. Every such lookup increments a self-monitoring counter named
+/// `<metric>_overflow_total`, so that overflows can be alerted on.
+/// A trailing `_total` is stripped from the metric name, so the counter
+/// for `requests_total` is named `requests_overflow_total`.
+///
+/// Label combinations that were already seen are not affected by the limit.
+/// The overflow series does not count towards it.
+///
+/// This wrapper is created by the derive macro when a field has
+/// the `max_series` option:
+///
+/// ```
+/// # use prometheus_metric_storage::{LimitedVec, MetricStorage};
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of trades by token.
+///     #[metric(labels("token"), max_series = 2, overflow = "other")]
+///     trades: LimitedVec<prometheus::IntCounterVec>,
+/// }
+///
+/// let registry = prometheus::Registry::new();
+/// let metrics = Metrics::new(&registry).unwrap();
+///
+/// metrics.trades.with_label_values(&["WETH"]).inc();
+/// metrics.trades.with_label_values(&["USDC"]).inc();
+/// metrics.trades.with_label_values(&["COW"]).inc();
+/// metrics.trades.with_label_values(&["GNO"]).inc();
+///
+/// assert_eq!(metrics.trades.with_label_values(&["WETH"]).get(), 1);
+/// assert_eq!(metrics.trades.with_label_values(&["other"]).get(), 2);
+/// assert_eq!(metrics.trades.overflow_count(), 2);
+/// ```
+///
+/// This wrapper doesn't implement [`MetricInit`], because metric options
+/// don't carry the limit. Outside of storages, create the underlying
+/// collector and wrap it via [`LimitedVec::new`].
+///
+/// [`MetricInit`]: crate::MetricInit
+#[derive(Clone, Debug)]
+pub struct LimitedVec<V> {
+    /// The underlying collector.
+    inner: V,
+
+    /// State shared between clones of this collector.
+    state: Arc<LimitedVecState>,
+}
+
+#[derive(Debug)]
+struct LimitedVecState {
+    /// Maximum number of label combinations.
+    max_series: usize,
+
+    /// Label value used for the overflow series.
+    overflow: String,
+
+    /// Label combinations that are currently stored in the collector.
+    series: Mutex<HashSet<Vec<String>>>,
+
+    /// Number of lookups folded into the overflow series.
+    overflow_counter: IntCounter,
+}
+
+impl<P: MetricVecBuilder> LimitedVec<MetricVec<P>> {
+    /// Wrap the given collector.
+    ///
+    /// Options are used to create the self-monitoring counter. They should
+    /// be the same options that were used to create the collector.
+    pub fn new(
+        inner: MetricVec<P>,
+        opts: &Opts,
+        max_series: usize,
+        overflow: impl Into<String>,
+    ) -> Result<Self> {
+        let counter_opts = Opts {
+            name: format!(
+                "{}_overflow_total",
+                opts.name.strip_suffix("_total").unwrap_or(&opts.name)
+            ),
+            help: format!(
+                "Number of lookups of `{}` folded into the overflow series.",
+                opts.fq_name()
+            ),
+            variable_labels: Vec::new(),
+            ..opts.clone()
+        };
+
+        Ok(Self {
+            inner,
+            state: Arc::new(LimitedVecState {
+                max_series,
+                overflow: overflow.into(),
+                series: Default::default(),
+                overflow_counter: IntCounter::with_opts(counter_opts)?,
+            }),
+        })
+    }
+
+    /// Return a reference to the underlying collector.
+    ///
+    /// Lookups performed via the underlying collector bypass the limit.
+    pub fn inner(&self) -> &MetricVec<P> {
+        &self.inner
+    }
+
+    /// Get the number of lookups that were folded into the overflow series.
+    pub fn overflow_count(&self) -> u64 {
+        self.state.overflow_counter.get()
+    }
+
+    /// Return the metric with the given label values, or the overflow
+    /// series if the limit is reached.
+    ///
+    /// See [`MetricVec::get_metric_with_label_values`] for more info.
+    pub fn get_metric_with_label_values(&self, vals: &[&str]) -> Result<P::M> {
+        let mut series = self.state.series.lock().unwrap();
+
+        if vals.iter().all(|&val| val == self.state.overflow) {
+            return self.inner.get_metric_with_label_values(vals);
+        }
+
+        let key: Vec<_> = vals.iter().map(|&val| val.to_string()).collect();
+
+        if series.contains(&key) || series.len() < self.state.max_series {
+            let metric = self.inner.get_metric_with_label_values(vals)?;
+            series.insert(key);
+            Ok(metric)
+        } else {
+            let overflow = vec![self.state.overflow.as_str(); vals.len()];
+            let metric = self.inner.get_metric_with_label_values(&overflow)?;
+            self.state.overflow_counter.inc();
+            Ok(metric)
+        }
+    }
+
+    /// Same as [`get_metric_with_label_values`], but panics on error.
+    ///
+    /// [`get_metric_with_label_values`]: LimitedVec::get_metric_with_label_values
+    pub fn with_label_values(&self, vals: &[&str]) -> P::M {
+        self.get_metric_with_label_values(vals)
+            .unwrap_or_else(|err| panic!("error {:?} when getting metric", err))
+    }
+
+    /// Remove the metric with the given label values, freeing
+    /// a slot for a new label combination.
+    ///
+    /// See [`MetricVec::remove_label_values`] for more info.
+    pub fn remove_label_values(&self, vals: &[&str]) -> Result<()> {
+        let mut series = self.state.series.lock().unwrap();
+
+        self.inner.remove_label_values(vals)?;
+
+        let key: Vec<_> = vals.iter().map(|&val| val.to_string()).collect();
+        series.remove(&key);
+
+        Ok(())
+    }
+
+    /// Remove all metrics, including the overflow series.
+    pub fn reset(&self) {
+        let mut series = self.state.series.lock().unwrap();
+
+        self.inner.reset();
+        series.clear();
+    }
+}
+
+impl<V: Collector> Collector for LimitedVec<V> {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut desc = self.inner.desc();
+        desc.extend(self.state.overflow_counter.desc());
+        desc
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.inner.collect();
+        families.extend(self.state.overflow_counter.collect());
+        families
+    }
+}
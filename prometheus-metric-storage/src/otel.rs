@@ -0,0 +1,186 @@
+//! Bridge for exporting metrics via OpenTelemetry.
+
+use crate::{Registry, StorageRegistry};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::metrics::data::{
+    self, DataPoint, Gauge, Histogram, HistogramDataPoint, ScopeMetrics, Sum, Temporality,
+};
+use opentelemetry_sdk::metrics::reader::MetricProducer;
+use opentelemetry_sdk::InstrumentationLibrary;
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use std::time::SystemTime;
+
+/// An OpenTelemetry [`MetricProducer`] that exports contents
+/// of a [`StorageRegistry`].
+///
+/// Add this producer to an OpenTelemetry metric reader, such as
+/// `PeriodicReader`, via its `with_producer` method. Every time the reader
+/// collects metrics, this producer gathers the registry and converts
+/// the results into OpenTelemetry data:
+///
+/// - counters become monotonic cumulative sums;
+/// - gauges and untyped metrics become gauges;
+/// - histograms become histograms with explicit buckets;
+/// - const and variable labels become attributes.
+///
+/// This way, the same metric storages can feed both Prometheus
+/// and OpenTelemetry pipelines.
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, OpenTelemetryProducer, StorageRegistry};
+/// use opentelemetry_sdk::metrics::data::Sum;
+/// use opentelemetry_sdk::metrics::reader::MetricProducer;
+///
+/// #[derive(MetricStorage)]
+/// #[metric(labels("endpoint"))]
+/// struct Metrics {
+///     /// Number of processed requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// let registry = StorageRegistry::default();
+/// Metrics::instance(&registry, "/api").unwrap().requests.inc();
+///
+/// let producer = OpenTelemetryProducer::new(&registry);
+/// let metrics = producer.produce().unwrap();
+///
+/// let requests = &metrics.metrics[0];
+/// assert_eq!(requests.name, "requests");
+///
+/// let sum = requests.data.as_any().downcast_ref::<Sum<f64>>().unwrap();
+/// assert!(sum.is_monotonic);
+/// assert_eq!(sum.data_points[0].value, 1.0);
+/// assert_eq!(sum.data_points[0].attributes[0].key.as_str(), "endpoint");
+/// ```
+#[derive(Clone, Debug)]
+pub struct OpenTelemetryProducer {
+    registry: Registry,
+    start_time: SystemTime,
+}
+
+impl OpenTelemetryProducer {
+    /// Create a new producer for the given registry.
+    ///
+    /// The time of this call will be reported as the start time
+    /// of all cumulative metrics.
+    pub fn new(registry: &StorageRegistry) -> Self {
+        Self {
+            registry: registry.registry().clone(),
+            start_time: SystemTime::now(),
+        }
+    }
+
+    fn convert(&self, family: &MetricFamily, time: SystemTime) -> Option<data::Metric> {
+        let metrics = family.get_metric();
+
+        let data: Box<dyn data::Aggregation> = match family.get_field_type() {
+            MetricType::COUNTER => Box::new(Sum {
+                data_points: metrics
+                    .iter()
+                    .map(|m| self.data_point(m, m.get_counter().get_value(), time))
+                    .collect(),
+                temporality: Temporality::Cumulative,
+                is_monotonic: true,
+            }),
+            MetricType::GAUGE => Box::new(Gauge {
+                data_points: metrics
+                    .iter()
+                    .map(|m| self.data_point(m, m.get_gauge().get_value(), time))
+                    .collect(),
+            }),
+            MetricType::UNTYPED => Box::new(Gauge {
+                data_points: metrics
+                    .iter()
+                    .map(|m| self.data_point(m, m.get_untyped().get_value(), time))
+                    .collect(),
+            }),
+            MetricType::HISTOGRAM => Box::new(Histogram {
+                data_points: metrics
+                    .iter()
+                    .map(|m| self.histogram_data_point(m, time))
+                    .collect(),
+                temporality: Temporality::Cumulative,
+            }),
+            // Summaries have no counterpart in OpenTelemetry.
+            MetricType::SUMMARY => return None,
+        };
+
+        Some(data::Metric {
+            name: family.get_name().to_string().into(),
+            description: family.get_help().to_string().into(),
+            unit: "".into(),
+            data,
+        })
+    }
+
+    fn data_point(&self, metric: &Metric, value: f64, time: SystemTime) -> DataPoint<f64> {
+        DataPoint {
+            attributes: attributes(metric),
+            start_time: Some(self.start_time),
+            time: Some(time),
+            value,
+            exemplars: Vec::new(),
+        }
+    }
+
+    fn histogram_data_point(&self, metric: &Metric, time: SystemTime) -> HistogramDataPoint<f64> {
+        let histogram = metric.get_histogram();
+
+        // Prometheus buckets are cumulative, OpenTelemetry ones are not.
+        // OpenTelemetry also has an explicit bucket for values
+        // above the last bound.
+        let mut bounds = Vec::new();
+        let mut bucket_counts = Vec::new();
+        let mut seen = 0;
+        for bucket in histogram.get_bucket() {
+            if bucket.get_upper_bound().is_infinite() {
+                continue;
+            }
+            bounds.push(bucket.get_upper_bound());
+            bucket_counts.push(bucket.get_cumulative_count().saturating_sub(seen));
+            seen = bucket.get_cumulative_count();
+        }
+        bucket_counts.push(histogram.get_sample_count().saturating_sub(seen));
+
+        HistogramDataPoint {
+            attributes: attributes(metric),
+            start_time: self.start_time,
+            time,
+            count: histogram.get_sample_count(),
+            bounds,
+            bucket_counts,
+            min: None,
+            max: None,
+            sum: histogram.get_sample_sum(),
+            exemplars: Vec::new(),
+        }
+    }
+}
+
+impl MetricProducer for OpenTelemetryProducer {
+    fn produce(&self) -> opentelemetry::metrics::Result<ScopeMetrics> {
+        let time = SystemTime::now();
+
+        Ok(ScopeMetrics {
+            scope: InstrumentationLibrary::builder(env!("CARGO_PKG_NAME"))
+                .with_version(env!("CARGO_PKG_VERSION"))
+                .build(),
+            metrics: self
+                .registry
+                .gather()
+                .iter()
+                .filter_map(|family| self.convert(family, time))
+                .collect(),
+        })
+    }
+}
+
+fn attributes(metric: &Metric) -> Vec<KeyValue> {
+    metric
+        .get_label()
+        .iter()
+        .map(|label| KeyValue::new(label.get_name().to_string(), label.get_value().to_string()))
+        .collect()
+}
@@ -0,0 +1,252 @@
+//! Push-based export to a Prometheus Pushgateway.
+
+use crate::worker::{Backoff, Worker};
+use crate::{Registry, StorageRegistry};
+use prometheus::{Error, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+
+impl StorageRegistry {
+    /// Push all metrics from this registry to a Pushgateway.
+    ///
+    /// All previously pushed metrics with the same job and grouping labels
+    /// are replaced. Metrics must not contain the `job` label or any
+    /// of the grouping labels.
+    ///
+    /// The `url` can be just `host:port`, in which case `http://`
+    /// is added automatically. It should not include
+    /// the `/metrics/job/...` part.
+    ///
+    /// This function performs a blocking HTTP request. It should not
+    /// be called from within an async runtime.
+    ///
+    /// See [`prometheus::push_metrics`] for more info.
+    pub fn push_to_gateway(
+        &self,
+        url: &str,
+        job: &str,
+        grouping_labels: HashMap<String, String>,
+    ) -> Result<()> {
+        prometheus::push_metrics(job, grouping_labels, url, self.gather(), None)
+    }
+}
+
+/// Builder for [`Pusher`].
+#[derive(Clone, Debug)]
+pub struct PusherBuilder {
+    url: String,
+    job: String,
+    grouping_labels: HashMap<String, String>,
+    interval: Duration,
+    backoff: Backoff,
+}
+
+impl PusherBuilder {
+    /// Set grouping labels for pushed metrics.
+    pub fn grouping_labels(mut self, grouping_labels: HashMap<String, String>) -> Self {
+        self.grouping_labels = grouping_labels;
+        self
+    }
+
+    /// Set interval between pushes. Default is 15 seconds.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set number of retries for a single failed push. Default is 3.
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.backoff.max_retries = max_retries;
+        self
+    }
+
+    /// Set delay before the first retry. Each next retry will wait
+    /// twice as long as the previous one, up to the [`max_backoff`].
+    /// Default is 100 milliseconds.
+    ///
+    /// [`max_backoff`]: PusherBuilder::max_backoff
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.backoff.initial = initial_backoff;
+        self
+    }
+
+    /// Set maximum delay between retries. Default is 5 seconds.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.backoff.max = max_backoff;
+        self
+    }
+
+    /// Start pushing metrics from the given registry in a background thread.
+    pub fn spawn(self, registry: &StorageRegistry) -> Pusher {
+        let registry = registry.registry().clone();
+        let worker = Worker::spawn("metrics-pusher", self.interval, move || {
+            Ok(move || self.push(&registry))
+        });
+
+        Pusher { worker }
+    }
+
+    fn push(&self, registry: &Registry) -> Result<()> {
+        self.backoff.retry(
+            || {
+                prometheus::push_metrics(
+                    &self.job,
+                    self.grouping_labels.clone(),
+                    &self.url,
+                    registry.gather(),
+                    None,
+                )
+            },
+            is_transient,
+        )
+    }
+}
+
+/// Check whether a failed push is worth retrying.
+///
+/// [`prometheus::push_metrics`] reports all failures as [`Error::Msg`],
+/// so we have to tell them apart by their messages. Invalid job names
+/// and conflicting labels will fail again, and so will pushes rejected
+/// with a client error, except for rate limits. Everything else,
+/// i.e. connection errors and server errors, is likely temporary.
+fn is_transient(err: &Error) -> bool {
+    let msg = match err {
+        Error::Msg(msg) => msg,
+        _ => return false,
+    };
+
+    if let Some(rest) = msg.strip_prefix("unexpected status code ") {
+        return match rest.get(..3).and_then(|code| code.parse::<u16>().ok()) {
+            Some(code) => !(400..500).contains(&code) || code == 429,
+            None => true,
+        };
+    }
+
+    !(msg.starts_with("job contains '/'")
+        || msg.starts_with("value of grouping label ")
+        || msg.starts_with("pushed metric "))
+}
+
+/// Periodically pushes metrics to a Pushgateway in a background thread.
+///
+/// Failed pushes are retried with exponential backoff, and then again
+/// at the next interval. Pushes that can't succeed on retry, such as those
+/// rejected by the Pushgateway with a client error, are not retried until
+/// the next interval. Metrics are also pushed when the pusher
+/// is [shut down](Pusher::shutdown) or dropped, so that batch jobs
+/// can report their final state before exiting.
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, Pusher, StorageRegistry};
+/// # use std::io::{BufRead, BufReader, Read, Write};
+/// # use std::time::Duration;
+/// # let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+/// # let url = listener.local_addr().unwrap().to_string();
+/// # let gateway = std::thread::spawn(move || {
+/// #     let (stream, _) = listener.accept().unwrap();
+/// #     let mut stream = BufReader::new(stream);
+/// #     let mut request_line = String::new();
+/// #     stream.read_line(&mut request_line).unwrap();
+/// #     let mut content_length = 0;
+/// #     loop {
+/// #         let mut line = String::new();
+/// #         stream.read_line(&mut line).unwrap();
+/// #         if line.trim().is_empty() { break; }
+/// #         if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+/// #             content_length = value.trim().parse().unwrap();
+/// #         }
+/// #     }
+/// #     let mut body = vec![0; content_length];
+/// #     stream.read_exact(&mut body).unwrap();
+/// #     stream.get_mut().write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+/// #     request_line
+/// # });
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of processed items.
+///     items_processed: prometheus::IntCounter,
+/// }
+///
+/// let registry = StorageRegistry::default();
+/// let pusher = Pusher::builder(&url, "batch_job")
+///     .interval(Duration::from_secs(60))
+///     .spawn(&registry);
+///
+/// Metrics::instance(&registry).unwrap().items_processed.inc();
+///
+/// // Final push happens on shutdown.
+/// pusher.shutdown().unwrap();
+/// # assert!(gateway.join().unwrap().starts_with("PUT /metrics/job/batch_job "));
+/// ```
+///
+/// Rejected pushes are reported, but not retried:
+///
+/// ```
+/// # use prometheus_metric_storage::{Pusher, StorageRegistry};
+/// # use std::io::{BufRead, BufReader, Read, Write};
+/// # use std::time::Duration;
+/// # let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+/// # let url = listener.local_addr().unwrap().to_string();
+/// # let gateway = std::thread::spawn(move || {
+/// #     let (stream, _) = listener.accept().unwrap();
+/// #     let mut stream = BufReader::new(stream);
+/// #     let mut content_length = 0;
+/// #     loop {
+/// #         let mut line = String::new();
+/// #         stream.read_line(&mut line).unwrap();
+/// #         if line.trim().is_empty() { break; }
+/// #         if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+/// #             content_length = value.trim().parse().unwrap();
+/// #         }
+/// #     }
+/// #     let mut body = vec![0; content_length];
+/// #     stream.read_exact(&mut body).unwrap();
+/// #     stream.get_mut().write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n").unwrap();
+/// #     listener
+/// # });
+/// let registry = StorageRegistry::default();
+/// let pusher = Pusher::builder(&url, "batch_job")
+///     .interval(Duration::from_secs(60))
+///     .max_retries(3)
+///     .initial_backoff(Duration::from_millis(1))
+///     .spawn(&registry);
+///
+/// let err = pusher.shutdown().unwrap_err();
+/// assert!(matches!(
+///     err,
+///     prometheus::Error::Msg(msg) if msg.starts_with("unexpected status code 400"),
+/// ));
+/// # // Pushgateway was contacted only once.
+/// # let listener = gateway.join().unwrap();
+/// # listener.set_nonblocking(true).unwrap();
+/// # assert!(listener.accept().is_err());
+/// ```
+#[derive(Debug)]
+pub struct Pusher {
+    worker: Worker,
+}
+
+impl Pusher {
+    /// Create a builder for a pusher that will push metrics to the given
+    /// Pushgateway url under the given job name.
+    ///
+    /// See [`StorageRegistry::push_to_gateway`] for more info.
+    pub fn builder(url: impl Into<String>, job: impl Into<String>) -> PusherBuilder {
+        PusherBuilder {
+            url: url.into(),
+            job: job.into(),
+            grouping_labels: HashMap::new(),
+            interval: Duration::from_secs(15),
+            backoff: Backoff::default(),
+        }
+    }
+
+    /// Stop the background thread and push metrics one last time.
+    ///
+    /// Returns the result of the final push.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.worker.stop()
+    }
+}
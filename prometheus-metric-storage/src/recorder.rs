@@ -0,0 +1,400 @@
+//! Recorder for the `metrics` facade backed by a storage registry.
+
+use crate::{Registry, StorageRegistry};
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata,
+    SharedString, Unit,
+};
+use prometheus::core::Collector;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// A pattern for matching metric names.
+///
+/// See [`MetricsRecorderBuilder::buckets_for`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Matcher {
+    /// Matches the whole name.
+    Full(String),
+
+    /// Matches the beginning of the name.
+    Prefix(String),
+
+    /// Matches the end of the name.
+    Suffix(String),
+}
+
+impl Matcher {
+    /// Check if the given metric name matches this pattern.
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Matcher::Full(full) => name == full,
+            Matcher::Prefix(prefix) => name.starts_with(prefix.as_str()),
+            Matcher::Suffix(suffix) => name.ends_with(suffix.as_str()),
+        }
+    }
+}
+
+/// Builder for [`MetricsRecorder`].
+#[derive(Clone, Debug)]
+pub struct MetricsRecorderBuilder {
+    registry: Registry,
+    buckets: Vec<(Matcher, Vec<f64>)>,
+    default_buckets: Vec<f64>,
+}
+
+impl MetricsRecorderBuilder {
+    /// Set histogram buckets for metrics with names matching the given
+    /// pattern. Patterns are checked in the order they were added.
+    pub fn buckets_for(mut self, matcher: Matcher, buckets: Vec<f64>) -> Self {
+        self.buckets.push((matcher, buckets));
+        self
+    }
+
+    /// Set histogram buckets for metrics that don't match any pattern.
+    ///
+    /// Default is [`prometheus::DEFAULT_BUCKETS`].
+    pub fn default_buckets(mut self, buckets: Vec<f64>) -> Self {
+        self.default_buckets = buckets;
+        self
+    }
+
+    /// Build the recorder.
+    pub fn build(self) -> MetricsRecorder {
+        MetricsRecorder {
+            state: Arc::new(RecorderState {
+                config: self,
+                descriptions: Default::default(),
+                families: Default::default(),
+            }),
+        }
+    }
+
+    /// Build the recorder and install it as the global recorder
+    /// for the `metrics` facade.
+    ///
+    /// Returns an error if a global recorder was already installed.
+    pub fn install(self) -> prometheus::Result<()> {
+        metrics::set_global_recorder(self.build())
+            .map_err(|err| prometheus::Error::Msg(err.to_string()))
+    }
+}
+
+/// A [`metrics::Recorder`] that creates collectors in a [`StorageRegistry`].
+///
+/// This recorder maps calls to the `metrics` facade, such as `counter!`,
+/// `gauge!` and `histogram!`, into prometheus collectors registered
+/// in the given registry. This way, metrics from third-party crates
+/// end up in the same registry as metric storages.
+///
+/// Every metric name becomes a metric vector with labels taken from
+/// the metric key. Characters that are not allowed in prometheus metric
+/// names are replaced with underscores. Descriptions provided via
+/// `describe_*!` macros become help messages; they should be provided
+/// before the metric is first used.
+///
+/// If a metric can't be registered, for example because its name
+/// clashes with an existing one, the facade calls are silently ignored.
+/// The same happens if a metric is used with label names that differ
+/// from the ones it was first used with.
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{Matcher, MetricsRecorder, StorageRegistry};
+/// let registry = StorageRegistry::default();
+/// let recorder = MetricsRecorder::builder(&registry)
+///     .buckets_for(Matcher::Suffix("_seconds".to_string()), vec![0.1, 1.0])
+///     .build();
+///
+/// metrics::with_local_recorder(&recorder, || {
+///     metrics::counter!("http.requests", "method" => "GET").increment(1);
+///     metrics::histogram!("http.latency_seconds").record(0.5);
+/// });
+///
+/// let families = registry.gather();
+/// assert_eq!(families[0].get_name(), "http_latency_seconds");
+/// assert_eq!(families[0].get_metric()[0].get_histogram().get_bucket().len(), 2);
+/// assert_eq!(families[1].get_name(), "http_requests");
+/// assert_eq!(families[1].get_metric()[0].get_counter().get_value(), 1.0);
+/// ```
+///
+/// Here's what happens when label names don't match:
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricsRecorder, StorageRegistry};
+/// let registry = StorageRegistry::default();
+/// let recorder = MetricsRecorder::builder(&registry).build();
+///
+/// metrics::with_local_recorder(&recorder, || {
+///     metrics::counter!("jobs", "queue" => "default").increment(1);
+///     // Ignored, since `jobs` was first used with the `queue` label.
+///     metrics::counter!("jobs", "worker" => "2").increment(5);
+/// });
+///
+/// let families = registry.gather();
+/// let metric = &families[0].get_metric()[0];
+/// assert_eq!(families[0].get_metric().len(), 1);
+/// assert_eq!(metric.get_label()[0].get_name(), "queue");
+/// assert_eq!(metric.get_counter().get_value(), 1.0);
+/// ```
+#[derive(Clone, Debug)]
+pub struct MetricsRecorder {
+    state: Arc<RecorderState>,
+}
+
+#[derive(Debug)]
+struct RecorderState {
+    config: MetricsRecorderBuilder,
+
+    /// Help messages by metric name.
+    descriptions: Mutex<HashMap<String, String>>,
+
+    /// Registered collectors by metric name.
+    families: Mutex<HashMap<String, Family>>,
+}
+
+#[derive(Debug)]
+struct Family {
+    /// Sorted names of labels the metric was first used with.
+    labels: Vec<String>,
+
+    collector: FamilyCollector,
+}
+
+#[derive(Debug)]
+enum FamilyCollector {
+    Counter(prometheus::IntCounterVec),
+    Gauge(prometheus::GaugeVec),
+    Histogram(prometheus::HistogramVec),
+    /// Metric could not be registered.
+    Invalid,
+}
+
+impl MetricsRecorder {
+    /// Create a builder for a recorder that will register collectors
+    /// in the given registry.
+    pub fn builder(registry: &StorageRegistry) -> MetricsRecorderBuilder {
+        MetricsRecorderBuilder {
+            registry: registry.registry().clone(),
+            buckets: Vec::new(),
+            default_buckets: prometheus::DEFAULT_BUCKETS.to_vec(),
+        }
+    }
+
+    fn describe(&self, key: KeyName, description: SharedString) {
+        self.state
+            .descriptions
+            .lock()
+            .unwrap()
+            .insert(sanitize(key.as_str()), description.into_owned());
+    }
+
+    /// Find a collector for the given key, create and register it
+    /// if it doesn't exist, then get a metric with label values
+    /// from the key.
+    ///
+    /// Returns `None` if label names of the key don't match the ones
+    /// the collector was created with.
+    fn lookup<T>(
+        &self,
+        key: &Key,
+        create: impl FnOnce(prometheus::Opts) -> prometheus::Result<FamilyCollector>,
+        get: impl FnOnce(&FamilyCollector, &[&str]) -> Option<T>,
+    ) -> Option<T> {
+        let name = sanitize(key.name());
+
+        let mut labels: Vec<_> = key
+            .labels()
+            .map(|label| (sanitize(label.key()), label.value()))
+            .collect();
+        labels.sort_unstable();
+        let label_names: Vec<_> = labels.iter().map(|(name, _)| name.clone()).collect();
+        let label_values: Vec<_> = labels.iter().map(|(_, value)| *value).collect();
+
+        let mut families = self.state.families.lock().unwrap();
+
+        let family = families.entry(name.clone()).or_insert_with(|| {
+            let help = self
+                .state
+                .descriptions
+                .lock()
+                .unwrap()
+                .get(&name)
+                .cloned()
+                .unwrap_or_else(|| name.clone());
+            let opts = prometheus::Opts::new(name, help).variable_labels(label_names.clone());
+
+            let collector = match create(opts) {
+                Ok(collector) => self.register(collector),
+                Err(_) => FamilyCollector::Invalid,
+            };
+
+            Family {
+                labels: label_names.clone(),
+                collector,
+            }
+        });
+
+        if family.labels != label_names {
+            return None;
+        }
+
+        get(&family.collector, &label_values)
+    }
+
+    /// Register the given collector, or return `Invalid` if it can't
+    /// be registered.
+    fn register(&self, collector: FamilyCollector) -> FamilyCollector {
+        let registered: Box<dyn Collector> = match &collector {
+            FamilyCollector::Counter(c) => Box::new(c.clone()),
+            FamilyCollector::Gauge(c) => Box::new(c.clone()),
+            FamilyCollector::Histogram(c) => Box::new(c.clone()),
+            FamilyCollector::Invalid => return collector,
+        };
+
+        match self.state.config.registry.register(registered) {
+            Ok(()) => collector,
+            Err(_) => FamilyCollector::Invalid,
+        }
+    }
+
+    fn buckets(&self, name: &str) -> Vec<f64> {
+        let name = sanitize(name);
+        self.state
+            .config
+            .buckets
+            .iter()
+            .find(|(matcher, _)| matcher.matches(&name))
+            .map_or(&self.state.config.default_buckets, |(_, buckets)| buckets)
+            .clone()
+    }
+}
+
+impl metrics::Recorder for MetricsRecorder {
+    fn describe_counter(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, _unit: Option<Unit>, description: SharedString) {
+        self.describe(key, description)
+    }
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        let counter = self.lookup(
+            key,
+            |opts| {
+                let labels: Vec<_> = opts.variable_labels.clone();
+                let labels: Vec<_> = labels.iter().map(String::as_str).collect();
+                prometheus::IntCounterVec::new(opts, &labels).map(FamilyCollector::Counter)
+            },
+            |family, values| match family {
+                FamilyCollector::Counter(c) => c.get_metric_with_label_values(values).ok(),
+                _ => None,
+            },
+        );
+
+        match counter {
+            Some(counter) => Counter::from_arc(Arc::new(CounterHandle(counter))),
+            None => Counter::noop(),
+        }
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        let gauge = self.lookup(
+            key,
+            |opts| {
+                let labels: Vec<_> = opts.variable_labels.clone();
+                let labels: Vec<_> = labels.iter().map(String::as_str).collect();
+                prometheus::GaugeVec::new(opts, &labels).map(FamilyCollector::Gauge)
+            },
+            |family, values| match family {
+                FamilyCollector::Gauge(c) => c.get_metric_with_label_values(values).ok(),
+                _ => None,
+            },
+        );
+
+        match gauge {
+            Some(gauge) => Gauge::from_arc(Arc::new(GaugeHandle(gauge))),
+            None => Gauge::noop(),
+        }
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        let buckets = self.buckets(key.name());
+        let histogram = self.lookup(
+            key,
+            |opts| {
+                let labels: Vec<_> = opts.variable_labels.clone();
+                let labels: Vec<_> = labels.iter().map(String::as_str).collect();
+                let opts = prometheus::HistogramOpts::from(opts).buckets(buckets);
+                prometheus::HistogramVec::new(opts, &labels).map(FamilyCollector::Histogram)
+            },
+            |family, values| match family {
+                FamilyCollector::Histogram(c) => c.get_metric_with_label_values(values).ok(),
+                _ => None,
+            },
+        );
+
+        match histogram {
+            Some(histogram) => Histogram::from_arc(Arc::new(HistogramHandle(histogram))),
+            None => Histogram::noop(),
+        }
+    }
+}
+
+struct CounterHandle(prometheus::IntCounter);
+
+impl CounterFn for CounterHandle {
+    fn increment(&self, value: u64) {
+        self.0.inc_by(value)
+    }
+
+    fn absolute(&self, value: u64) {
+        // Prometheus counters can't be set, but they can catch up.
+        let current = self.0.get();
+        if value > current {
+            self.0.inc_by(value - current)
+        }
+    }
+}
+
+struct GaugeHandle(prometheus::Gauge);
+
+impl GaugeFn for GaugeHandle {
+    fn increment(&self, value: f64) {
+        self.0.add(value)
+    }
+
+    fn decrement(&self, value: f64) {
+        self.0.sub(value)
+    }
+
+    fn set(&self, value: f64) {
+        self.0.set(value)
+    }
+}
+
+struct HistogramHandle(prometheus::Histogram);
+
+impl HistogramFn for HistogramHandle {
+    fn record(&self, value: f64) {
+        self.0.observe(value)
+    }
+}
+
+/// Replace characters that are not allowed in prometheus names.
+fn sanitize(name: &str) -> String {
+    let mut result: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if result.starts_with(|c: char| c.is_ascii_digit()) {
+        result.insert(0, '_');
+    }
+    result
+}
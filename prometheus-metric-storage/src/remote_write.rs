@@ -0,0 +1,486 @@
+//! Export via the Prometheus remote-write protocol.
+
+use crate::worker::{Backoff, Worker};
+use crate::StorageRegistry;
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use prometheus::{Error, Result};
+use std::collections::VecDeque;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Protobuf messages of the remote-write protocol.
+mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct WriteRequest {
+        #[prost(message, repeated, tag = "1")]
+        pub timeseries: Vec<TimeSeries>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TimeSeries {
+        #[prost(message, repeated, tag = "1")]
+        pub labels: Vec<Label>,
+        #[prost(message, repeated, tag = "2")]
+        pub samples: Vec<Sample>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Label {
+        #[prost(string, tag = "1")]
+        pub name: String,
+        #[prost(string, tag = "2")]
+        pub value: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Sample {
+        #[prost(double, tag = "1")]
+        pub value: f64,
+        #[prost(int64, tag = "2")]
+        pub timestamp: i64,
+    }
+}
+
+/// Builder for [`RemoteWriter`].
+#[derive(Clone, Debug)]
+pub struct RemoteWriterBuilder {
+    url: String,
+    headers: Vec<(String, String)>,
+    interval: Duration,
+    timeout: Duration,
+    max_series_per_send: usize,
+    queue_capacity: usize,
+    backoff: Backoff,
+}
+
+impl RemoteWriterBuilder {
+    /// Add an HTTP header to every request, e.g. for authorization.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Set interval between gathering metrics. Default is 15 seconds.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set timeout for a single request. Default is 10 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Set maximum number of series sent in a single request.
+    /// Default is 500.
+    pub fn max_series_per_send(mut self, max_series_per_send: usize) -> Self {
+        self.max_series_per_send = max_series_per_send.max(1);
+        self
+    }
+
+    /// Set maximum number of series waiting to be sent. When the queue
+    /// is full, the oldest series are dropped. Default is 10000.
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    /// Set number of retries for a single failed request. Default is 3.
+    pub fn max_retries(mut self, max_retries: usize) -> Self {
+        self.backoff.max_retries = max_retries;
+        self
+    }
+
+    /// Set delay before the first retry. Each next retry will wait
+    /// twice as long as the previous one, up to the [`max_backoff`].
+    /// Default is 100 milliseconds.
+    ///
+    /// [`max_backoff`]: RemoteWriterBuilder::max_backoff
+    pub fn initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.backoff.initial = initial_backoff;
+        self
+    }
+
+    /// Set maximum delay between retries. Default is 5 seconds.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.backoff.max = max_backoff;
+        self
+    }
+
+    /// Start sending metrics from the given registry in a background thread.
+    pub fn spawn(self, registry: &StorageRegistry) -> RemoteWriter {
+        let registry = registry.registry().clone();
+        let worker = Worker::spawn("metrics-remote-writer", self.interval, move || {
+            // Blocking client can't be created within an async runtime,
+            // so it's created in the background thread.
+            let client = reqwest::blocking::Client::builder()
+                .timeout(self.timeout)
+                .build()
+                .map_err(|err| Error::Msg(err.to_string()))?;
+
+            let mut state = RemoteWriterState {
+                config: self,
+                client,
+                queue: VecDeque::new(),
+            };
+
+            Ok(move || {
+                state.enqueue(&registry.gather());
+                state.flush()
+            })
+        });
+
+        RemoteWriter { worker }
+    }
+}
+
+struct RemoteWriterState {
+    config: RemoteWriterBuilder,
+    client: reqwest::blocking::Client,
+    queue: VecDeque<proto::TimeSeries>,
+}
+
+impl RemoteWriterState {
+    fn enqueue(&mut self, families: &[MetricFamily]) {
+        self.queue.extend(to_timeseries(families, now_millis()));
+
+        let overflow = self.queue.len().saturating_sub(self.config.queue_capacity);
+        self.queue.drain(..overflow);
+    }
+
+    /// Send all queued series. Stops at the first batch that could not
+    /// be sent, leaving it and the rest of the queue for the next attempt.
+    /// Batches that were rejected by the endpoint are dropped.
+    fn flush(&mut self) -> Result<()> {
+        let mut result = Ok(());
+
+        while !self.queue.is_empty() {
+            let len = self.queue.len().min(self.config.max_series_per_send);
+            let request = proto::WriteRequest {
+                timeseries: self.queue.range(..len).cloned().collect(),
+            };
+
+            match self.send(&request) {
+                Ok(()) => {}
+                Err(SendError::Permanent(err)) => result = Err(err),
+                Err(SendError::Transient(err)) => return Err(err),
+            }
+
+            self.queue.drain(..len);
+        }
+
+        result
+    }
+
+    fn send(&self, request: &proto::WriteRequest) -> std::result::Result<(), SendError> {
+        let body = snap::raw::Encoder::new()
+            .compress_vec(&prost::Message::encode_to_vec(request))
+            .map_err(|err| SendError::Permanent(Error::Msg(err.to_string())))?;
+
+        self.config.backoff.retry(
+            || self.send_once(body.clone()),
+            |err| matches!(err, SendError::Transient(_)),
+        )
+    }
+
+    fn send_once(&self, body: Vec<u8>) -> std::result::Result<(), SendError> {
+        let mut request = self
+            .client
+            .post(&self.config.url)
+            .header("Content-Encoding", "snappy")
+            .header("Content-Type", "application/x-protobuf")
+            .header("X-Prometheus-Remote-Write-Version", "0.1.0");
+        for (name, value) in &self.config.headers {
+            request = request.header(name, value);
+        }
+
+        let response = request
+            .body(body)
+            .send()
+            .map_err(|err| SendError::Transient(Error::Msg(err.to_string())))?;
+
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else {
+            let err = Error::Msg(format!(
+                "unexpected status code {} while writing to {}",
+                status, self.config.url
+            ));
+            // Client errors mean that the data is malformed, resending
+            // it won't help. Except for rate limits, which are temporary.
+            if status.is_client_error() && status.as_u16() != 429 {
+                Err(SendError::Permanent(err))
+            } else {
+                Err(SendError::Transient(err))
+            }
+        }
+    }
+}
+
+enum SendError {
+    /// Request can be retried.
+    Transient(Error),
+
+    /// Request should be dropped.
+    Permanent(Error),
+}
+
+/// Periodically sends metrics to a remote-write endpoint
+/// in a background thread.
+///
+/// Metrics are gathered at a fixed interval, converted to remote-write
+/// time series, and put into a bounded queue. The queue is then sent
+/// in batches, each batch encoded as a snappy-compressed protobuf
+/// `WriteRequest`. Failed requests are retried with exponential backoff;
+/// if an endpoint is unavailable for a long time, the oldest series
+/// are dropped from the queue.
+///
+/// The queue is flushed once more when the writer is
+/// [shut down](RemoteWriter::shutdown) or dropped.
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, RemoteWriter, StorageRegistry};
+/// # use std::io::{BufRead, BufReader, Read, Write};
+/// # use std::time::Duration;
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct WriteRequest {
+/// #     #[prost(message, repeated, tag = "1")]
+/// #     timeseries: Vec<TimeSeries>,
+/// # }
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct TimeSeries {
+/// #     #[prost(message, repeated, tag = "1")]
+/// #     labels: Vec<Label>,
+/// #     #[prost(message, repeated, tag = "2")]
+/// #     samples: Vec<Sample>,
+/// # }
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct Label {
+/// #     #[prost(string, tag = "1")]
+/// #     name: String,
+/// #     #[prost(string, tag = "2")]
+/// #     value: String,
+/// # }
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct Sample {
+/// #     #[prost(double, tag = "1")]
+/// #     value: f64,
+/// #     #[prost(int64, tag = "2")]
+/// #     timestamp: i64,
+/// # }
+/// # let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+/// # let url = format!("http://{}/api/v1/write", listener.local_addr().unwrap());
+/// # let endpoint = std::thread::spawn(move || {
+/// #     let mut batches = Vec::new();
+/// #     for _ in 0..3 {
+/// #         let (stream, _) = listener.accept().unwrap();
+/// #         let mut stream = BufReader::new(stream);
+/// #         let mut request = String::new();
+/// #         let mut content_length = 0;
+/// #         loop {
+/// #             let mut line = String::new();
+/// #             stream.read_line(&mut line).unwrap();
+/// #             if line.trim().is_empty() { break; }
+/// #             if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+/// #                 content_length = value.trim().parse().unwrap();
+/// #             }
+/// #             request.push_str(&line.to_lowercase());
+/// #         }
+/// #         assert!(request.starts_with("post /api/v1/write "));
+/// #         assert!(request.contains("content-encoding: snappy"));
+/// #         assert!(request.contains("content-type: application/x-protobuf"));
+/// #         let mut body = vec![0; content_length];
+/// #         stream.read_exact(&mut body).unwrap();
+/// #         stream.get_mut().write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+/// #         let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+/// #         let request = <WriteRequest as prost::Message>::decode(&*body).unwrap();
+/// #         let batch: Vec<String> = request
+/// #             .timeseries
+/// #             .iter()
+/// #             .map(|series| {
+/// #                 let labels: Vec<_> = series
+/// #                     .labels
+/// #                     .iter()
+/// #                     .map(|label| format!("{}={:?}", label.name, label.value))
+/// #                     .collect();
+/// #                 assert_eq!(series.samples.len(), 1);
+/// #                 format!("{{{}}} {}", labels.join(", "), series.samples[0].value)
+/// #             })
+/// #             .collect();
+/// #         batches.push(batch);
+/// #     }
+/// #     batches
+/// # });
+/// #[derive(MetricStorage)]
+/// #[metric(subsystem = "worker")]
+/// struct Metrics {
+///     /// Number of processed items.
+///     #[metric(labels("queue"))]
+///     items_processed: prometheus::IntCounterVec,
+///
+///     /// Processing time of each item in seconds.
+///     #[metric(buckets(1))]
+///     processing_time_seconds: prometheus::Histogram,
+/// }
+///
+/// let registry = StorageRegistry::default();
+/// let writer = RemoteWriter::builder(url)
+///     .interval(Duration::from_secs(60))
+///     .max_series_per_send(2)
+///     .spawn(&registry);
+///
+/// let metrics = Metrics::instance(&registry).unwrap();
+/// metrics.items_processed.with_label_values(&["fast"]).inc_by(3);
+/// metrics.processing_time_seconds.observe(0.5);
+/// metrics.processing_time_seconds.observe(2.0);
+///
+/// // Final write happens on shutdown.
+/// writer.shutdown().unwrap();
+///
+/// // Histograms are split into series the same way as in the text format,
+/// // labels of each series are sorted by name, and series are sent
+/// // in batches of `max_series_per_send`.
+/// # let batches = endpoint.join().unwrap();
+/// assert_eq!(batches, [
+///     vec![
+///         r#"{__name__="worker_items_processed", queue="fast"} 3"#,
+///         r#"{__name__="worker_processing_time_seconds_bucket", le="1"} 1"#,
+///     ],
+///     vec![
+///         r#"{__name__="worker_processing_time_seconds_bucket", le="+Inf"} 2"#,
+///         r#"{__name__="worker_processing_time_seconds_sum"} 2.5"#,
+///     ],
+///     vec![
+///         r#"{__name__="worker_processing_time_seconds_count"} 2"#,
+///     ],
+/// ]);
+/// ```
+#[derive(Debug)]
+pub struct RemoteWriter {
+    worker: Worker,
+}
+
+impl RemoteWriter {
+    /// Create a builder for a writer that will send metrics
+    /// to the given remote-write endpoint.
+    pub fn builder(url: impl Into<String>) -> RemoteWriterBuilder {
+        RemoteWriterBuilder {
+            url: url.into(),
+            headers: Vec::new(),
+            interval: Duration::from_secs(15),
+            timeout: Duration::from_secs(10),
+            max_series_per_send: 500,
+            queue_capacity: 10000,
+            backoff: Backoff::default(),
+        }
+    }
+
+    /// Stop the background thread and send metrics one last time.
+    ///
+    /// Returns the result of the final write.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.worker.stop()
+    }
+}
+
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as i64)
+}
+
+/// Convert gathered metric families to remote-write time series.
+///
+/// Histograms and summaries are split into multiple series,
+/// the same way they're represented in the text exposition format.
+fn to_timeseries(families: &[MetricFamily], timestamp: i64) -> Vec<proto::TimeSeries> {
+    let mut result = Vec::new();
+
+    for family in families {
+        let name = family.get_name();
+
+        for metric in family.get_metric() {
+            let timestamp = match metric.get_timestamp_ms() {
+                0 => timestamp,
+                ts => ts,
+            };
+            let mut push = |suffix: &str, extra: Option<(&str, String)>, value: f64| {
+                result.push(make_timeseries(
+                    format!("{}{}", name, suffix),
+                    metric,
+                    extra,
+                    value,
+                    timestamp,
+                ));
+            };
+
+            match family.get_field_type() {
+                MetricType::COUNTER => push("", None, metric.get_counter().get_value()),
+                MetricType::GAUGE => push("", None, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => push("", None, metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    for bucket in histogram.get_bucket() {
+                        push(
+                            "_bucket",
+                            Some(("le", bucket.get_upper_bound().to_string())),
+                            bucket.get_cumulative_count() as f64,
+                        );
+                    }
+                    push(
+                        "_bucket",
+                        Some(("le", "+Inf".to_string())),
+                        histogram.get_sample_count() as f64,
+                    );
+                    push("_sum", None, histogram.get_sample_sum());
+                    push("_count", None, histogram.get_sample_count() as f64);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        push(
+                            "",
+                            Some(("quantile", quantile.get_quantile().to_string())),
+                            quantile.get_value(),
+                        );
+                    }
+                    push("_sum", None, summary.get_sample_sum());
+                    push("_count", None, summary.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+
+    result
+}
+
+fn make_timeseries(
+    name: String,
+    metric: &Metric,
+    extra: Option<(&str, String)>,
+    value: f64,
+    timestamp: i64,
+) -> proto::TimeSeries {
+    let mut labels = vec![proto::Label {
+        name: "__name__".to_string(),
+        value: name,
+    }];
+    labels.extend(metric.get_label().iter().map(|label| proto::Label {
+        name: label.get_name().to_string(),
+        value: label.get_value().to_string(),
+    }));
+    labels.extend(extra.map(|(name, value)| proto::Label {
+        name: name.to_string(),
+        value,
+    }));
+    // Remote-write requires labels to be sorted by name.
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    proto::TimeSeries {
+        labels,
+        samples: vec![proto::Sample { value, timestamp }],
+    }
+}
@@ -0,0 +1,417 @@
+//! Export to a StatsD or DogStatsD agent.
+
+use crate::worker::Worker;
+use crate::{Registry, StorageRegistry};
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use prometheus::{Error, Result};
+use std::collections::HashMap;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+/// Builder for [`StatsdExporter`].
+#[derive(Clone, Debug)]
+pub struct StatsdExporterBuilder {
+    addr: String,
+    prefix: String,
+    interval: Duration,
+    max_packet_size: usize,
+}
+
+impl StatsdExporterBuilder {
+    /// Set a prefix that will be added to all metric names, separated
+    /// by a dot. Default is no prefix.
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    /// Set interval between exports. Default is 10 seconds.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set maximum size of a single UDP packet. Multiple metrics are
+    /// batched into one packet, separated by newlines, as long as they fit.
+    /// Default is 1432 bytes, which is safe for most networks.
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Start exporting metrics from the given registry in a background thread.
+    ///
+    /// Returns an error if the agent address can't be resolved,
+    /// or if a UDP socket can't be created.
+    pub fn spawn(self, registry: &StorageRegistry) -> Result<StatsdExporter> {
+        let addr =
+            self.addr.to_socket_addrs()?.next().ok_or_else(|| {
+                Error::Msg(format!("can't resolve statsd address {:?}", self.addr))
+            })?;
+
+        let socket = if addr.is_ipv4() {
+            UdpSocket::bind(("0.0.0.0", 0))?
+        } else {
+            UdpSocket::bind(("::", 0))?
+        };
+        socket.connect(addr)?;
+
+        let mut state = StatsdState {
+            prefix: self.prefix,
+            max_packet_size: self.max_packet_size,
+            socket,
+            counters: HashMap::new(),
+            histograms: HashMap::new(),
+        };
+
+        // Failed sends are not retried, as with any UDP traffic.
+        let registry = registry.registry().clone();
+        let worker = Worker::spawn("metrics-statsd", self.interval, move || {
+            Ok(move || state.export(&registry))
+        });
+
+        Ok(StatsdExporter { worker })
+    }
+}
+
+/// Periodically sends metrics to a StatsD or DogStatsD agent
+/// in a background thread.
+///
+/// Every interval, the exporter gathers the registry and converts
+/// the results into StatsD lines:
+///
+/// - counters are sent as counts (`|c`) of increments since
+///   the previous export;
+/// - gauges and untyped metrics are sent as gauges (`|g`);
+/// - histograms are sent as histogram observations (`|h`). Since
+///   prometheus only keeps bucket counts, each new observation is reported
+///   as the upper bound of its bucket, and multiple observations
+///   in the same bucket are sent as one line with a sample rate;
+/// - summaries are not exported.
+///
+/// Const and variable labels are sent as DogStatsD tags (`|#name:value`).
+/// This extension is understood by the Datadog agent, Telegraf
+/// and the Prometheus `statsd_exporter`.
+///
+/// Metrics are exported once more when the exporter is
+/// [shut down](StatsdExporter::shutdown) or dropped.
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, StatsdExporter, StorageRegistry};
+/// # use std::time::Duration;
+/// #[derive(MetricStorage)]
+/// #[metric(labels("endpoint"))]
+/// struct Metrics {
+///     /// Number of processed requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// let agent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+/// let addr = agent.local_addr().unwrap().to_string();
+///
+/// let registry = StorageRegistry::default();
+/// let exporter = StatsdExporter::builder(addr)
+///     .prefix("app")
+///     .interval(Duration::from_secs(60))
+///     .spawn(&registry)
+///     .unwrap();
+///
+/// Metrics::instance(&registry, "/api").unwrap().requests.inc_by(3);
+///
+/// // Final export happens on shutdown.
+/// exporter.shutdown().unwrap();
+///
+/// let mut packet = [0; 1500];
+/// let len = agent.recv(&mut packet).unwrap();
+/// assert_eq!(&packet[..len], b"app.requests:3|c|#endpoint:/api");
+/// ```
+///
+/// Each export only reports what changed since the previous one:
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, StatsdExporter, StorageRegistry};
+/// # use std::time::Duration;
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Processing time of each request in seconds.
+///     #[metric(buckets(0.1, 1))]
+///     request_duration_seconds: prometheus::Histogram,
+///
+///     /// Number of processed requests.
+///     requests: prometheus::IntCounter,
+/// }
+///
+/// let agent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+/// let addr = agent.local_addr().unwrap().to_string();
+/// let mut packet = [0; 1500];
+///
+/// let registry = StorageRegistry::default();
+/// let metrics = Metrics::instance(&registry).unwrap();
+/// metrics.request_duration_seconds.observe(0.05);
+/// metrics.request_duration_seconds.observe(0.05);
+/// metrics.request_duration_seconds.observe(0.5);
+/// metrics.requests.inc_by(3);
+///
+/// let exporter = StatsdExporter::builder(addr)
+///     .interval(Duration::from_millis(500))
+///     .spawn(&registry)
+///     .unwrap();
+///
+/// // First export reports each observation as the upper bound
+/// // of its bucket, with a sample rate for repeated values.
+/// let len = agent.recv(&mut packet).unwrap();
+/// assert_eq!(
+///     std::str::from_utf8(&packet[..len]).unwrap(),
+///     "request_duration_seconds:0.1|h|@0.5\n\
+///      request_duration_seconds:1|h\n\
+///      requests:3|c",
+/// );
+///
+/// metrics.request_duration_seconds.observe(0.5);
+/// metrics.request_duration_seconds.observe(0.5);
+/// metrics.request_duration_seconds.observe(5.0);
+/// metrics.requests.reset();
+/// metrics.requests.inc_by(2);
+///
+/// exporter.shutdown().unwrap();
+///
+/// // Final export only reports new observations. Observations above
+/// // the last bucket are reported as its bound. After a counter reset,
+/// // its whole value is reported.
+/// let len = agent.recv(&mut packet).unwrap();
+/// assert_eq!(
+///     std::str::from_utf8(&packet[..len]).unwrap(),
+///     "request_duration_seconds:1|h|@0.5\n\
+///      request_duration_seconds:1|h\n\
+///      requests:2|c",
+/// );
+/// ```
+#[derive(Debug)]
+pub struct StatsdExporter {
+    worker: Worker,
+}
+
+impl StatsdExporter {
+    /// Create a builder for an exporter that will send metrics
+    /// to the agent at the given address, such as `127.0.0.1:8125`.
+    pub fn builder(addr: impl Into<String>) -> StatsdExporterBuilder {
+        StatsdExporterBuilder {
+            addr: addr.into(),
+            prefix: String::new(),
+            interval: Duration::from_secs(10),
+            max_packet_size: 1432,
+        }
+    }
+
+    /// Stop the background thread and export metrics one last time.
+    ///
+    /// Returns the result of the final export.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.worker.stop()
+    }
+}
+
+struct StatsdState {
+    prefix: String,
+    max_packet_size: usize,
+    socket: UdpSocket,
+
+    /// Counter values at the previous export, by series.
+    counters: HashMap<String, f64>,
+
+    /// Cumulative bucket counts and sample sum at the previous export,
+    /// by series. The last count is the total sample count.
+    histograms: HashMap<String, (Vec<u64>, f64)>,
+}
+
+impl StatsdState {
+    fn export(&mut self, registry: &Registry) -> Result<()> {
+        let mut lines = Vec::new();
+        let mut counters = HashMap::new();
+        let mut histograms = HashMap::new();
+
+        for family in registry.gather() {
+            let name = self.name(&family);
+            for metric in family.get_metric() {
+                let tags = tags(metric);
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let key = format!("{}{}", name, tags);
+                        let value = metric.get_counter().get_value();
+                        let delta = match self.counters.get(&key) {
+                            // Counter was reset, report everything since the reset.
+                            Some(&previous) if value < previous => value,
+                            Some(&previous) => value - previous,
+                            None => value,
+                        };
+                        if delta > 0.0 && delta.is_finite() {
+                            lines.push(format!("{}:{}|c{}", name, delta, tags));
+                        }
+                        counters.insert(key, value);
+                    }
+                    MetricType::GAUGE => {
+                        gauge(&mut lines, &name, metric.get_gauge().get_value(), &tags)
+                    }
+                    MetricType::UNTYPED => {
+                        gauge(&mut lines, &name, metric.get_untyped().get_value(), &tags)
+                    }
+                    MetricType::HISTOGRAM => {
+                        let key = format!("{}{}", name, tags);
+                        let state = self.histogram(&mut lines, &name, &key, metric, &tags);
+                        histograms.insert(key, state);
+                    }
+                    MetricType::SUMMARY => {}
+                }
+            }
+        }
+
+        // Forget series that no longer exist.
+        self.counters = counters;
+        self.histograms = histograms;
+
+        self.send(lines)
+    }
+
+    fn histogram(
+        &self,
+        lines: &mut Vec<String>,
+        name: &str,
+        key: &str,
+        metric: &Metric,
+        tags: &str,
+    ) -> (Vec<u64>, f64) {
+        let histogram = metric.get_histogram();
+
+        let mut bounds = Vec::new();
+        let mut counts = Vec::new();
+        for bucket in histogram.get_bucket() {
+            if bucket.get_upper_bound().is_finite() {
+                bounds.push(bucket.get_upper_bound());
+                counts.push(bucket.get_cumulative_count());
+            }
+        }
+        counts.push(histogram.get_sample_count());
+        let sum = histogram.get_sample_sum();
+
+        let (previous, previous_sum) = match self.histograms.get(key) {
+            // Histogram was reset, report everything since the reset.
+            Some((previous, _)) if previous.last() > counts.last() => (None, 0.0),
+            Some((previous, sum)) if previous.len() == counts.len() => (Some(previous), *sum),
+            _ => (None, 0.0),
+        };
+
+        let mut seen = 0;
+        for (i, &count) in counts.iter().enumerate() {
+            let previous_count = previous.map_or(0, |previous| previous[i]);
+            let new_observations = count.saturating_sub(previous_count).saturating_sub(seen);
+            seen += new_observations;
+
+            if new_observations == 0 {
+                continue;
+            }
+
+            // Observations above the last bucket are reported as its bound;
+            // without buckets, all observations are reported as their mean.
+            let value = match bounds.get(i).or_else(|| bounds.last()) {
+                Some(&bound) => bound,
+                None => (sum - previous_sum) / new_observations as f64,
+            };
+            if !value.is_finite() {
+                continue;
+            }
+
+            if new_observations == 1 {
+                lines.push(format!("{}:{}|h{}", name, value, tags));
+            } else {
+                let rate = 1.0 / new_observations as f64;
+                lines.push(format!("{}:{}|h|@{}{}", name, value, rate, tags));
+            }
+        }
+
+        (counts, sum)
+    }
+
+    fn name(&self, family: &MetricFamily) -> String {
+        if self.prefix.is_empty() {
+            family.get_name().to_string()
+        } else {
+            format!("{}.{}", self.prefix, family.get_name())
+        }
+    }
+
+    /// Send lines to the agent, batching as many of them
+    /// into a single packet as possible.
+    fn send(&self, lines: Vec<String>) -> Result<()> {
+        let mut result = Ok(());
+        let mut packet = String::new();
+
+        for line in lines {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > self.max_packet_size {
+                result = result.and(self.send_packet(&packet));
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+
+        if !packet.is_empty() {
+            result = result.and(self.send_packet(&packet));
+        }
+
+        result
+    }
+
+    fn send_packet(&self, packet: &str) -> Result<()> {
+        self.socket.send(packet.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn gauge(lines: &mut Vec<String>, name: &str, value: f64, tags: &str) {
+    if !value.is_finite() {
+        return;
+    }
+
+    // In StatsD, a negative value is a decrement. To set a gauge
+    // to a negative value, it has to be set to zero first.
+    if value < 0.0 {
+        lines.push(format!("{}:0|g{}", name, tags));
+    }
+    lines.push(format!("{}:{}|g{}", name, value, tags));
+}
+
+/// Format labels as DogStatsD tags.
+fn tags(metric: &Metric) -> String {
+    let labels = metric.get_label();
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let tags: Vec<_> = labels
+        .iter()
+        .map(|label| {
+            format!(
+                "{}:{}",
+                label.get_name(),
+                sanitize_tag_value(label.get_value())
+            )
+        })
+        .collect();
+
+    format!("|#{}", tags.join(","))
+}
+
+/// Replace characters that have special meaning in DogStatsD lines.
+fn sanitize_tag_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ',' | '|' | '#' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
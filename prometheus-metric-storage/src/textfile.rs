@@ -0,0 +1,160 @@
+//! Export to a node_exporter textfile collector.
+
+use crate::worker::Worker;
+use crate::{Registry, StorageRegistry};
+use prometheus::{Encoder, Error, Result, TextEncoder};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+impl StorageRegistry {
+    /// Write all metrics from this registry to a file
+    /// in the text exposition format.
+    ///
+    /// The file is written atomically: metrics are first written
+    /// to a temporary file in the same directory, which is then renamed
+    /// to the given path. This way, node_exporter's textfile collector
+    /// never sees a partially written file. Name of the temporary file
+    /// doesn't end with `.prom`, so the collector ignores it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use prometheus_metric_storage::{MetricStorage, StorageRegistry};
+    /// #[derive(MetricStorage)]
+    /// struct Metrics {
+    ///     /// Number of processed items.
+    ///     items_processed: prometheus::IntCounter,
+    /// }
+    ///
+    /// let registry = StorageRegistry::default();
+    /// Metrics::instance(&registry).unwrap().items_processed.inc();
+    ///
+    /// let path = std::env::temp_dir().join(format!("batch_job_{}.prom", std::process::id()));
+    /// registry.write_textfile(&path).unwrap();
+    ///
+    /// let text = std::fs::read_to_string(&path).unwrap();
+    /// assert!(text.contains("items_processed 1\n"));
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    pub fn write_textfile(&self, path: impl AsRef<Path>) -> Result<()> {
+        write_textfile(self.registry(), path.as_ref())
+    }
+}
+
+fn write_textfile(registry: &Registry, path: &Path) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| Error::Msg(format!("invalid textfile path {:?}", path)))?;
+
+    let mut tmp_name = file_name.to_os_string();
+    tmp_name.push(format!(".{}.tmp", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&registry.gather(), &mut buffer)?;
+
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(&buffer)?;
+            file.sync_all()
+        })
+        .and_then(|()| std::fs::rename(&tmp_path, path));
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+
+    Ok(result?)
+}
+
+/// Builder for [`TextfileWriter`].
+#[derive(Clone, Debug)]
+pub struct TextfileWriterBuilder {
+    path: PathBuf,
+    interval: Duration,
+}
+
+impl TextfileWriterBuilder {
+    /// Set interval between writes. Default is 15 seconds.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Start writing metrics from the given registry in a background thread.
+    pub fn spawn(self, registry: &StorageRegistry) -> TextfileWriter {
+        let registry = registry.registry().clone();
+        let path = self.path;
+        let worker = Worker::spawn("metrics-textfile", self.interval, move || {
+            Ok(move || write_textfile(&registry, &path))
+        });
+
+        TextfileWriter { worker }
+    }
+}
+
+/// Periodically writes metrics to a file for node_exporter's
+/// textfile collector in a background thread.
+///
+/// This allows cron-style jobs to publish metrics without running
+/// an HTTP endpoint. See [`StorageRegistry::write_textfile`] for details
+/// on how the file is written.
+///
+/// The file is written once more when the writer is
+/// [shut down](TextfileWriter::shutdown) or dropped, so that it contains
+/// the final state of the job.
+///
+/// # Example
+///
+/// ```
+/// # use prometheus_metric_storage::{MetricStorage, StorageRegistry, TextfileWriter};
+/// # use std::time::Duration;
+/// #[derive(MetricStorage)]
+/// struct Metrics {
+///     /// Number of processed items.
+///     items_processed: prometheus::IntCounter,
+/// }
+///
+/// let path = std::env::temp_dir().join(format!("cron_job_{}.prom", std::process::id()));
+///
+/// let registry = StorageRegistry::default();
+/// let writer = TextfileWriter::builder(&path)
+///     .interval(Duration::from_secs(60))
+///     .spawn(&registry);
+///
+/// Metrics::instance(&registry).unwrap().items_processed.inc();
+///
+/// // Final write happens on shutdown.
+/// writer.shutdown().unwrap();
+///
+/// let text = std::fs::read_to_string(&path).unwrap();
+/// assert!(text.contains("items_processed 1\n"));
+/// # std::fs::remove_file(&path).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct TextfileWriter {
+    worker: Worker,
+}
+
+impl TextfileWriter {
+    /// Create a builder for a writer that will write metrics
+    /// to the given path.
+    ///
+    /// The path should point to a `*.prom` file in a directory watched
+    /// by node_exporter's textfile collector.
+    pub fn builder(path: impl Into<PathBuf>) -> TextfileWriterBuilder {
+        TextfileWriterBuilder {
+            path: path.into(),
+            interval: Duration::from_secs(15),
+        }
+    }
+
+    /// Stop the background thread and write metrics one last time.
+    ///
+    /// Returns the result of the final write.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.worker.stop()
+    }
+}
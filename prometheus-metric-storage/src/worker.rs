@@ -0,0 +1,129 @@
+//! Background threads of periodic exporters.
+
+use prometheus::{Error, Result};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Background thread that runs a task at a fixed interval.
+///
+/// When the worker is stopped, either explicitly via [`stop`] or by being
+/// dropped, it runs the task one last time, so that exporters can report
+/// the final state of a process before it exits.
+///
+/// [`stop`]: Worker::stop
+#[derive(Debug)]
+pub(crate) struct Worker {
+    name: String,
+    shutdown_tx: Sender<()>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Worker {
+    /// Spawn a thread with the given name. The thread calls `init`
+    /// to create the task, and then runs the task every `interval`.
+    ///
+    /// Errors of periodic runs are ignored, the task is expected to retry
+    /// at the next interval. Errors of `init` and of the final run
+    /// are returned from [`stop`].
+    ///
+    /// [`stop`]: Worker::stop
+    pub(crate) fn spawn<I, T>(name: &str, interval: Duration, init: I) -> Self
+    where
+        I: FnOnce() -> Result<T> + Send + 'static,
+        T: FnMut() -> Result<()>,
+    {
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+
+        let thread = std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                let mut task = init()?;
+                loop {
+                    match shutdown_rx.recv_timeout(interval) {
+                        Err(RecvTimeoutError::Timeout) => {
+                            let _ = task();
+                        }
+                        Ok(()) | Err(RecvTimeoutError::Disconnected) => return task(),
+                    }
+                }
+            })
+            .unwrap_or_else(|err| panic!("failed to spawn thread {:?}: {}", name, err));
+
+        Worker {
+            name: name.to_string(),
+            shutdown_tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// Stop the thread, and return the result of the final run.
+    ///
+    /// Does nothing if the worker was already stopped.
+    pub(crate) fn stop(&mut self) -> Result<()> {
+        let _ = self.shutdown_tx.send(());
+        match self.thread.take() {
+            Some(thread) => thread
+                .join()
+                .unwrap_or_else(|_| Err(Error::Msg(format!("thread {:?} panicked", self.name)))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+/// Exponential backoff between retries of a failed operation.
+#[cfg(any(feature = "push", feature = "remote-write"))]
+#[derive(Clone, Debug)]
+pub(crate) struct Backoff {
+    /// Number of retries after the first attempt.
+    pub(crate) max_retries: usize,
+
+    /// Delay before the first retry.
+    pub(crate) initial: Duration,
+
+    /// Maximum delay between retries.
+    pub(crate) max: Duration,
+}
+
+#[cfg(any(feature = "push", feature = "remote-write"))]
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            max_retries: 3,
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+#[cfg(any(feature = "push", feature = "remote-write"))]
+impl Backoff {
+    /// Run the operation, and retry it while it fails with errors
+    /// for which `is_transient` returns `true`. Each next retry waits
+    /// twice as long as the previous one.
+    pub(crate) fn retry<T, E>(
+        &self,
+        mut operation: impl FnMut() -> std::result::Result<T, E>,
+        is_transient: impl Fn(&E) -> bool,
+    ) -> std::result::Result<T, E> {
+        let mut delay = self.initial;
+        let mut retries = 0;
+
+        loop {
+            match operation() {
+                Err(err) if retries < self.max_retries && is_transient(&err) => {
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(self.max);
+                    retries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}